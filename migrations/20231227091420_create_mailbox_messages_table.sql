@@ -0,0 +1,11 @@
+-- Add migration script here
+-- Outgoing emails captured by the `mailbox` email provider during development.
+CREATE TABLE mailbox_messages (
+    message_id uuid PRIMARY KEY,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    headers JSONB NOT NULL,
+    received_at timestamptz NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Issues published before this migration have no known author.
+ALTER TABLE newsletter_issues
+    ADD COLUMN author_id uuid NULL REFERENCES users (user_id);
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE scheduled_newsletter_issues (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    send_at timestamptz NOT NULL,
+    PRIMARY KEY (newsletter_issue_id)
+);
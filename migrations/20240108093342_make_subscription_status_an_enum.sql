@@ -0,0 +1,13 @@
+-- Add migration script here
+BEGIN;
+CREATE TYPE subscription_status AS ENUM (
+    'pending_confirmation',
+    'confirmed',
+    'unsubscribed',
+    'bounced',
+    'complained'
+);
+ALTER TABLE subscriptions
+    ALTER COLUMN status TYPE subscription_status
+    USING status::subscription_status;
+COMMIT;
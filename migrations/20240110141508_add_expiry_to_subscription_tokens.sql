@@ -0,0 +1,10 @@
+-- Add migration script here
+BEGIN;
+ALTER TABLE subscription_tokens
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN expires_at timestamptz NULL,
+    ADD COLUMN consumed_at timestamptz NULL;
+-- Tokens handed out before this migration get the default window.
+UPDATE subscription_tokens SET expires_at = created_at + interval '24 hours';
+ALTER TABLE subscription_tokens ALTER COLUMN expires_at SET NOT NULL;
+COMMIT;
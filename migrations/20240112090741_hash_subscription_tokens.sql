@@ -0,0 +1,8 @@
+-- Add migration script here
+BEGIN;
+ALTER TABLE subscription_tokens RENAME COLUMN subscription_token TO token_hash;
+-- Tokens that are still outstanding are stored in plain text. The application
+-- hashes them with its HMAC secret when it starts (`hash_outstanding_tokens`).
+ALTER TABLE subscription_tokens ADD COLUMN is_hashed BOOLEAN NOT NULL DEFAULT true;
+UPDATE subscription_tokens SET is_hashed = false;
+COMMIT;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Scheduled issues carry their send time in `published_at`, so the creation
+-- time needs a column of its own. Earlier issues were created when published.
+ALTER TABLE newsletter_issues ADD COLUMN created_at timestamptz NULL;
+UPDATE newsletter_issues SET created_at = published_at;
+ALTER TABLE newsletter_issues ALTER COLUMN created_at SET NOT NULL;
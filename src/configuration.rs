@@ -1,16 +1,32 @@
 use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailSender, MailboxEmailClient, SmtpEmailClient};
 use secrecy::{ExposeSecret, Secret};
-use sqlx::{postgres::PgConnectOptions, PgConnection};
+use sqlx::{postgres::PgConnectOptions, PgPool};
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Clone, serde::Deserialize)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub issue_delivery: IssueDeliverySettings,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct IssueDeliverySettings {
+    pub max_attempts: i32,
+    pub base_backoff_milliseconds: u64,
+    pub max_backoff_milliseconds: u64,
+    /// How many queued deliveries a worker picks up at once.
+    pub batch_size: i64,
 }
 
 #[derive(Clone, serde::Deserialize)]
 pub struct EmailClientSettings {
+    #[serde(default)]
+    pub provider: EmailProvider,
+    pub smtp: Option<SmtpSettings>,
     pub base_url: String,
     pub sender_email: String,
     pub authorization_token: Secret<String>,
@@ -23,15 +39,104 @@ impl EmailClientSettings {
     }
 
     pub fn timeout(&self) -> std::time::Duration {
-        std::time::Duration::from_micros(self.timeout_milliseconds)
+        std::time::Duration::from_millis(self.timeout_milliseconds)
+    }
+
+    pub fn client(&self, connection_pool: &PgPool) -> Arc<dyn EmailSender> {
+        let sender_email = self.sender().expect("Invalid sender email address");
+        match self.provider {
+            EmailProvider::Postmark => Arc::new(EmailClient::new(
+                self.base_url.clone(),
+                sender_email,
+                self.authorization_token.clone(),
+                self.timeout(),
+            )),
+            EmailProvider::Smtp => {
+                let smtp = self
+                    .smtp
+                    .as_ref()
+                    .expect("The `smtp` provider requires `email_client.smtp` settings");
+                Arc::new(
+                    SmtpEmailClient::new(smtp, sender_email, self.timeout())
+                        .expect("Failed to build the SMTP email client"),
+                )
+            }
+            EmailProvider::Mailbox => {
+                Arc::new(MailboxEmailClient::new(connection_pool.clone(), sender_email))
+            }
+        }
     }
 }
 
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailProvider {
+    #[default]
+    Postmark,
+    Smtp,
+    /// Keeps outgoing emails in a local mailbox instead of sending them.
+    /// Meant for development only.
+    Mailbox,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain-text connection, only meant for local development.
+    None,
+    /// Upgrade the connection with `STARTTLS`, usually on port 587.
+    Starttls,
+    /// TLS from the first byte, usually on port 465.
+    Implicit,
+}
+
 #[derive(Clone, serde::Deserialize)]
 pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    /// Signs the links we hand out in emails, e.g. to unsubscribe.
+    pub hmac_secret: Secret<String>,
+    /// How long a confirmation link stays valid after it is sent.
+    pub confirmation_token_ttl_hours: u32,
+    /// How long an address has to wait before asking for another
+    /// confirmation email.
+    pub confirmation_resend_cooldown_seconds: u32,
+    #[serde(default)]
+    pub opt_in: OptIn,
+}
+
+/// Whether new subscribers have to confirm their address before they receive
+/// any newsletter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptIn {
+    /// New subscribers are confirmed straight away and get a welcome email;
+    /// those coming back after leaving still confirm again. Only meant for
+    /// internal lists, e.g. employee newsletters.
+    Single,
+    /// Subscribers get a confirmation link first.
+    #[default]
+    Double,
+}
+
+impl ApplicationSettings {
+    pub fn confirmation_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.confirmation_token_ttl_hours.into())
+    }
+
+    pub fn confirmation_resend_cooldown(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.confirmation_resend_cooldown_seconds.into())
+    }
 }
 
 #[derive(Clone, serde::Deserialize)]
@@ -44,21 +149,48 @@ pub struct DatabaseSettings {
 }
 
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
-    let mut settings = config::Config::default();
     let base_path = std::env::current_dir().expect("Failed to determine the current directory");
     let configuration_directory = base_path.join("configuration");
 
-    settings.merge(config::File::from(configuration_directory.join("base")).required(true))?;
-
     let environment: Environment = std::env::var("APP_ENVIRONMENT")
         .unwrap_or_else(|_| "local".into())
         .try_into()
         .expect("Failed to parse APP_ENVIRONMENT");
 
+    load_configuration(&configuration_directory, environment)
+}
+
+/// Reads `base` and then the file of `environment` from `configuration_directory`,
+/// then environment variables such as `APP_APPLICATION__HMAC_SECRET` for
+/// `application.hmac_secret`.
+fn load_configuration(
+    configuration_directory: &Path,
+    environment: Environment,
+) -> Result<Settings, config::ConfigError> {
+    let mut settings = config::Config::default();
+    settings.merge(config::File::from(configuration_directory.join("base")).required(true))?;
+    // Committed to the repository, hence known to anyone who can read it.
+    let default_hmac_secret: String = settings.get("application.hmac_secret")?;
     settings.merge(
         config::File::from(configuration_directory.join(environment.as_str())).required(true),
     )?;
-    settings.try_into()
+    settings.merge(config::Environment::with_prefix("app").separator("__"))?;
+    let settings: Settings = settings.try_into()?;
+    if let Environment::Production = environment {
+        if let EmailProvider::Mailbox = settings.email_client.provider {
+            return Err(config::ConfigError::Message(
+                "The `mailbox` email provider cannot be used in production".into(),
+            ));
+        }
+        if settings.application.hmac_secret.expose_secret() == &default_hmac_secret {
+            return Err(config::ConfigError::Message(
+                "The default `application.hmac_secret` cannot be used in production. \
+                Set APP_APPLICATION__HMAC_SECRET"
+                    .into(),
+            ));
+        }
+    }
+    Ok(settings)
 }
 
 impl DatabaseSettings {
@@ -122,3 +254,80 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{load_configuration, EmailProvider, Environment};
+    use claim::assert_ok;
+    use secrecy::ExposeSecret;
+    use std::path::PathBuf;
+
+    /// A configuration directory with the repository's `base` file and the
+    /// given `environment` file.
+    fn configuration_directory(environment: &Environment, contents: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("base.yaml"),
+            include_str!("../configuration/base.yaml"),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join(format!("{}.yaml", environment.as_str())),
+            contents,
+        )
+        .unwrap();
+        directory
+    }
+
+    const MAILBOX: &str = r#"
+application:
+  host: 0.0.0.0
+  base_url: https://example.com
+  hmac_secret: "not-the-default-one"
+email_client:
+  provider: "mailbox"
+"#;
+
+    const PRODUCTION: &str = r#"
+application:
+  host: 0.0.0.0
+  base_url: https://example.com
+"#;
+
+    #[test]
+    fn the_mailbox_provider_is_refused_in_production() {
+        let directory = configuration_directory(&Environment::Production, MAILBOX);
+        let e = load_configuration(&directory, Environment::Production)
+            .err()
+            .expect("The mailbox provider was accepted in production");
+        assert!(e.to_string().contains("`mailbox`"));
+    }
+
+    #[test]
+    fn the_mailbox_provider_is_accepted_locally() {
+        let directory = configuration_directory(&Environment::Local, MAILBOX);
+        let settings = assert_ok!(load_configuration(&directory, Environment::Local));
+        assert!(matches!(settings.email_client.provider, EmailProvider::Mailbox));
+    }
+
+    // Environment variables are shared by every test of the binary: keep this
+    // the only one setting `APP_APPLICATION__HMAC_SECRET`.
+    #[test]
+    fn the_default_hmac_secret_has_to_be_overridden_in_production() {
+        let directory = configuration_directory(&Environment::Production, PRODUCTION);
+        let e = load_configuration(&directory, Environment::Production)
+            .err()
+            .expect("The default HMAC secret was accepted in production");
+        assert!(e.to_string().contains("hmac_secret"));
+
+        std::env::set_var("APP_APPLICATION__HMAC_SECRET", "a-secret-of-our-own");
+        let settings = load_configuration(&directory, Environment::Production);
+        std::env::remove_var("APP_APPLICATION__HMAC_SECRET");
+        let settings = assert_ok!(settings);
+        assert_eq!(
+            settings.application.hmac_secret.expose_secret(),
+            "a-secret-of-our-own"
+        );
+    }
+}
@@ -25,10 +25,14 @@ impl SubscriberName {
         let is_empty_or_whitespace = s.trim().is_empty();
         let is_too_long = s.graphemes(true).count() > 256;
         let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contains_forbidden_characters = s.chars().any(|g| forbidden_characters.contains(&g));
+        let forbidden_character = s.chars().find(|g| forbidden_characters.contains(g));
 
-        if is_empty_or_whitespace || is_too_long || contains_forbidden_characters {
-            Err(format!("{} is not a valid subscriber name", s))
+        if is_empty_or_whitespace {
+            Err("A subscriber name cannot be empty.".into())
+        } else if is_too_long {
+            Err("A subscriber name cannot be longer than 256 characters.".into())
+        } else if let Some(c) = forbidden_character {
+            Err(format!("A subscriber name cannot contain `{}`.", c))
         } else {
             Ok(Self(s))
         }
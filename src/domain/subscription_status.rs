@@ -0,0 +1,80 @@
+/// Where a subscriber is in their lifecycle, stored as the
+/// `subscription_status` Postgres enum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "subscription_status", rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    Complained,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("A subscriber cannot go from {from:?} to {to:?}.")]
+pub struct InvalidTransition {
+    pub from: SubscriptionStatus,
+    pub to: SubscriptionStatus,
+}
+
+impl SubscriptionStatus {
+    /// Staying in the same state is always allowed, so that repeating an
+    /// action (e.g. clicking a link twice) is harmless.
+    pub fn transition_to(self, to: SubscriptionStatus) -> Result<SubscriptionStatus, InvalidTransition> {
+        use SubscriptionStatus::*;
+        let allowed = self == to
+            || matches!(
+                (self, to),
+                (PendingConfirmation, Confirmed | Unsubscribed | Bounced | Complained)
+                    | (Confirmed, Unsubscribed | Bounced | Complained)
+                    // Coming back means opting in again.
+                    | (Unsubscribed | Bounced, PendingConfirmation)
+            );
+        if allowed {
+            Ok(to)
+        } else {
+            Err(InvalidTransition { from: self, to })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionStatus::{self, *};
+    use claim::{assert_err, assert_ok};
+
+    const ALL: [SubscriptionStatus; 5] =
+        [PendingConfirmation, Confirmed, Unsubscribed, Bounced, Complained];
+
+    #[test]
+    fn repeating_a_transition_is_harmless() {
+        for status in ALL {
+            assert_ok!(status.transition_to(status));
+        }
+    }
+
+    #[test]
+    fn pending_subscribers_can_confirm() {
+        assert_eq!(PendingConfirmation.transition_to(Confirmed), Ok(Confirmed));
+    }
+
+    #[test]
+    fn subscribers_who_left_cannot_be_confirmed_without_opting_in_again() {
+        for status in [Unsubscribed, Bounced, Complained] {
+            assert_err!(status.transition_to(Confirmed));
+        }
+        assert_ok!(Unsubscribed.transition_to(PendingConfirmation));
+    }
+
+    #[test]
+    fn complaints_are_final() {
+        for status in ALL.into_iter().filter(|s| *s != Complained) {
+            assert_err!(Complained.transition_to(status));
+        }
+    }
+
+    #[test]
+    fn confirmed_subscribers_cannot_go_back_to_pending() {
+        assert_err!(Confirmed.transition_to(PendingConfirmation));
+    }
+}
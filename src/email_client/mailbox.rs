@@ -0,0 +1,58 @@
+use super::{EmailClientError, EmailSender, OutgoingEmail};
+use crate::domain::SubscriberEmail;
+use sqlx::PgPool;
+use uuid::Uuid;
+use std::sync::Arc;
+
+/// Stores outgoing emails in Postgres instead of sending them, so they can
+/// be inspected under `/dev/mailbox` during local development.
+pub struct MailboxEmailClient {
+    pool: PgPool,
+    sender: SubscriberEmail,
+}
+
+impl MailboxEmailClient {
+    pub fn new(pool: PgPool, sender: SubscriberEmail) -> Self {
+        Self { pool, sender }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for MailboxEmailClient {
+    #[tracing::instrument(name = "Store email in the development mailbox", skip_all)]
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<(), EmailClientError> {
+        let mut headers = vec![
+            serde_json::json!({"name": "From", "value": self.sender.as_ref()}),
+            serde_json::json!({"name": "To", "value": email.recipient.as_ref()}),
+            serde_json::json!({"name": "Subject", "value": email.subject}),
+        ];
+        for (name, value) in email.extra_headers() {
+            headers.push(serde_json::json!({"name": name, "value": value}));
+        }
+        let headers = serde_json::Value::from(headers);
+        sqlx::query!(
+            r#"
+            INSERT INTO mailbox_messages (
+                message_id,
+                recipient,
+                subject,
+                html_content,
+                text_content,
+                headers,
+                received_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, now())
+            "#,
+            Uuid::new_v4(),
+            email.recipient.as_ref(),
+            email.subject,
+            email.html_content,
+            email.text_content,
+            headers
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| EmailClientError::UnexpectedError(Arc::new(e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+mod mailbox;
+mod postmark;
+mod smtp;
+
+pub use mailbox::MailboxEmailClient;
+pub use postmark::EmailClient;
+pub use smtp::SmtpEmailClient;
+
+use crate::domain::SubscriberEmail;
+use crate::routes::error_chain_fmt;
+use reqwest::StatusCode;
+use std::sync::Arc;
+
+type SharedError = Arc<dyn std::error::Error + Send + Sync>;
+
+pub struct OutgoingEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    /// Where the recipient can leave the list, advertised through the
+    /// `List-Unsubscribe` headers.
+    pub unsubscribe_url: Option<&'a str>,
+}
+
+impl OutgoingEmail<'_> {
+    /// Headers to send on top of the usual ones: a one-click unsubscribe
+    /// (RFC 8058) when the email has an unsubscribe URL.
+    pub fn extra_headers(&self) -> Vec<(&'static str, String)> {
+        match self.unsubscribe_url {
+            Some(url) => vec![
+                ("List-Unsubscribe", format!("<{}>", url)),
+                ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".into()),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Anything that can deliver an email to a single recipient.
+#[async_trait::async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<(), EmailClientError>;
+
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<(), EmailClientError> {
+        self.send(&OutgoingEmail {
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            unsubscribe_url: None,
+        })
+        .await
+    }
+
+    /// Sends every email in `emails`, returning one result per email in the
+    /// same order. Providers with a batch API should override this.
+    async fn send_batch(&self, emails: &[OutgoingEmail<'_>]) -> Vec<Result<(), EmailClientError>> {
+        let mut results = Vec::with_capacity(emails.len());
+        for email in emails {
+            results.push(self.send(email).await);
+        }
+        results
+    }
+}
+
+#[derive(thiserror::Error, Clone)]
+pub enum EmailClientError {
+    #[error("The request to the email provider timed out.")]
+    Timeout(#[source] SharedError),
+    #[error("Failed to connect to the email provider.")]
+    Connection(#[source] SharedError),
+    #[error("The email provider is rate limiting our requests.")]
+    RateLimited,
+    #[error("The recipient is inactive or suppressed: {message}")]
+    InactiveRecipient { message: String },
+    #[error("The sender signature is invalid: {message}")]
+    InvalidSenderSignature { error_code: i64, message: String },
+    #[error("The email provider failed with status {status}: {message}")]
+    ServerError { status: StatusCode, message: String },
+    #[error("The email provider rejected the email with status {status} (error code {error_code}): {message}")]
+    Rejected {
+        status: StatusCode,
+        error_code: i64,
+        message: String,
+    },
+    #[error("The SMTP server replied with {code}: {message}")]
+    SmtpReply { code: u16, message: String },
+    #[error("Failed to send the request to the email provider.")]
+    UnexpectedError(#[source] SharedError),
+}
+
+impl std::fmt::Debug for EmailClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl EmailClientError {
+    /// Whether sending the same email again later has a chance of succeeding.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            EmailClientError::Timeout(_)
+            | EmailClientError::Connection(_)
+            | EmailClientError::RateLimited
+            | EmailClientError::ServerError { .. } => true,
+            // 4yz replies are transient failures, 5yz ones are permanent.
+            EmailClientError::SmtpReply { code, .. } => (400..500).contains(code),
+            EmailClientError::InactiveRecipient { .. }
+            | EmailClientError::InvalidSenderSignature { .. }
+            | EmailClientError::Rejected { .. }
+            | EmailClientError::UnexpectedError(_) => false,
+        }
+    }
+}
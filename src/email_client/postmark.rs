@@ -0,0 +1,568 @@
+use super::{EmailClientError, EmailSender, OutgoingEmail};
+use crate::domain::SubscriberEmail;
+use reqwest::{Client, StatusCode};
+use secrecy::{ExposeSecret, Secret};
+use std::sync::Arc;
+
+/// Postmark error code for recipients marked as inactive, either because
+/// they hard-bounced, complained or were manually suppressed.
+const INACTIVE_RECIPIENT: i64 = 406;
+/// Postmark error codes for a sender signature that is missing or unconfirmed.
+const SENDER_SIGNATURE_NOT_FOUND: i64 = 400;
+const SENDER_SIGNATURE_NOT_CONFIRMED: i64 = 401;
+/// Postmark rejects batches with more messages than this.
+const MAX_BATCH_SIZE: usize = 500;
+
+fn error_from_response(status: StatusCode, body: &str) -> EmailClientError {
+    let (error_code, message) = match serde_json::from_str::<PostmarkErrorResponse>(body) {
+        Ok(r) => (r.error_code, r.message),
+        Err(_) => (0, body.to_string()),
+    };
+    error_from_code(status, error_code, message)
+}
+
+fn error_from_code(status: StatusCode, error_code: i64, message: String) -> EmailClientError {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return EmailClientError::RateLimited;
+    }
+    if status.is_server_error() {
+        return EmailClientError::ServerError { status, message };
+    }
+    match error_code {
+        INACTIVE_RECIPIENT => EmailClientError::InactiveRecipient { message },
+        SENDER_SIGNATURE_NOT_FOUND | SENDER_SIGNATURE_NOT_CONFIRMED => {
+            EmailClientError::InvalidSenderSignature {
+                error_code,
+                message,
+            }
+        }
+        _ => EmailClientError::Rejected {
+            status,
+            error_code,
+            message,
+        },
+    }
+}
+
+impl From<reqwest::Error> for EmailClientError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            EmailClientError::Timeout(Arc::new(e))
+        } else if e.is_connect() {
+            EmailClientError::Connection(Arc::new(e))
+        } else {
+            EmailClientError::UnexpectedError(Arc::new(e))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EmailClient {
+    http_client: Client,
+    base_url: String,
+    sender: SubscriberEmail,
+    authorization_token: Secret<String>,
+}
+
+impl EmailClient {
+    pub fn new(
+        base_url: String,
+        sender: SubscriberEmail,
+        authorization_token: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            base_url,
+            sender,
+            authorization_token,
+        }
+    }
+
+    async fn send_batch_chunk(
+        &self,
+        emails: &[OutgoingEmail<'_>],
+    ) -> Result<Vec<Result<(), EmailClientError>>, EmailClientError> {
+        let url = format!("{}/email/batch", self.base_url);
+        let request_body: Vec<_> = emails
+            .iter()
+            .map(|email| SendEmailRequest::new(&self.sender, email))
+            .collect();
+        let response = self
+            .http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(&request_body)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await?;
+            return Err(error_from_response(status, &body));
+        }
+        let results: Vec<PostmarkErrorResponse> = response.json().await?;
+        if results.len() != emails.len() {
+            return Err(EmailClientError::ServerError {
+                status,
+                message: format!(
+                    "Expected {} results from the batch endpoint, got {}",
+                    emails.len(),
+                    results.len()
+                ),
+            });
+        }
+        Ok(results
+            .into_iter()
+            .map(|r| match r.error_code {
+                0 => Ok(()),
+                error_code => Err(error_from_code(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    error_code,
+                    r.message,
+                )),
+            })
+            .collect())
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for EmailClient {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<(), EmailClientError> {
+        let url = format!("{}/email", self.base_url);
+        let request_body = SendEmailRequest::new(&self.sender, email);
+        let response = self
+            .http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(&request_body)
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.text().await?;
+        Err(error_from_response(status, &body))
+    }
+
+    /// Sends the emails through Postmark's `/email/batch` endpoint,
+    /// `MAX_BATCH_SIZE` at a time.
+    async fn send_batch(&self, emails: &[OutgoingEmail<'_>]) -> Vec<Result<(), EmailClientError>> {
+        let mut results = Vec::with_capacity(emails.len());
+        for chunk in emails.chunks(MAX_BATCH_SIZE) {
+            match self.send_batch_chunk(chunk).await {
+                Ok(chunk_results) => results.extend(chunk_results),
+                Err(e) => results.extend(std::iter::repeat_n(Err(e), chunk.len())),
+            }
+        }
+        results
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkErrorResponse {
+    error_code: i64,
+    message: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<PostmarkHeader>,
+}
+
+impl<'a> SendEmailRequest<'a> {
+    fn new(sender: &'a SubscriberEmail, email: &OutgoingEmail<'a>) -> Self {
+        Self {
+            from: sender.as_ref(),
+            to: email.recipient.as_ref(),
+            subject: email.subject,
+            html_body: email.html_content,
+            text_body: email.text_content,
+            headers: email
+                .extra_headers()
+                .into_iter()
+                .map(|(name, value)| PostmarkHeader { name, value })
+                .collect(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkHeader {
+    name: &'static str,
+    value: String,
+}
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::postmark::EmailClient;
+    use crate::email_client::{EmailClientError, EmailSender, OutgoingEmail};
+    use claim::assert_err;
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use secrecy::Secret;
+    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            base_url,
+            email(),
+            Secret::new(Faker.fake()),
+            std::time::Duration::from_millis(200),
+        )
+    }
+
+    struct SendEmailBodyMatcher;
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_to_base_url() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let _ = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let _ = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn send_email_timeout_if_the_server_takes_too_long() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let _ = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn send_email_reports_rate_limiting_as_retryable() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+        let e = assert_err!(outcome);
+        assert!(matches!(e, EmailClientError::RateLimited));
+        assert!(e.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn send_email_parses_inactive_recipient_errors() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        }));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+        let e = assert_err!(outcome);
+        assert!(matches!(e, EmailClientError::InactiveRecipient { .. }));
+        assert!(!e.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn send_email_parses_invalid_sender_signature_errors() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 400,
+            "Message": "The 'From' address you supplied is not a Sender Signature on your account."
+        }));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+        let e = assert_err!(outcome);
+        assert!(matches!(
+            e,
+            EmailClientError::InvalidSenderSignature { error_code: 400, .. }
+        ));
+        assert!(!e.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn send_email_reports_server_errors_as_retryable() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+        let e = assert_err!(outcome);
+        assert!(matches!(e, EmailClientError::ServerError { .. }));
+        assert!(e.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn send_email_reports_timeouts_as_retryable() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+        let e = assert_err!(outcome);
+        assert!(matches!(e, EmailClientError::Timeout(_)));
+        assert!(e.is_retryable());
+    }
+
+    fn batch_response(n_messages: usize, failing_index: Option<usize>) -> ResponseTemplate {
+        let results: Vec<_> = (0..n_messages)
+            .map(|i| {
+                if Some(i) == failing_index {
+                    serde_json::json!({"ErrorCode": 406, "Message": "Inactive recipient"})
+                } else {
+                    serde_json::json!({"ErrorCode": 0, "Message": "OK"})
+                }
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_outcome_of_each_message() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(batch_response(3, Some(1)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recipients: Vec<_> = (0..3).map(|_| email()).collect();
+        let (subject, content) = (subject(), content());
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|recipient| OutgoingEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                unsubscribe_url: None,
+            })
+            .collect();
+        let results = email_client.send_batch(&emails).await;
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(EmailClientError::InactiveRecipient { .. })
+        ));
+        assert!(results[2].is_ok());
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_messages_into_chunks_of_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(|request: &Request| {
+                let messages: Vec<serde_json::Value> =
+                    serde_json::from_slice(&request.body).unwrap();
+                batch_response(messages.len(), None)
+            })
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let recipient = email();
+        let (subject, content) = (subject(), content());
+        let emails: Vec<_> = (0..501)
+            .map(|_| OutgoingEmail {
+                recipient: &recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                unsubscribe_url: None,
+            })
+            .collect();
+        let results = email_client.send_batch(&emails).await;
+
+        assert_eq!(results.len(), 501);
+        assert!(results.iter().all(|r| r.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_every_message_if_the_request_fails() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recipients: Vec<_> = (0..2).map(|_| email()).collect();
+        let (subject, content) = (subject(), content());
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|recipient| OutgoingEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                unsubscribe_url: None,
+            })
+            .collect();
+        let results = email_client.send_batch(&emails).await;
+
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Err(e) if e.is_retryable())));
+    }
+
+    #[tokio::test]
+    async fn send_batch_adds_one_click_unsubscribe_headers() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(batch_response(1, None))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recipient = email();
+        let (subject, content) = (subject(), content());
+        let emails = [OutgoingEmail {
+            recipient: &recipient,
+            subject: &subject,
+            html_content: &content,
+            text_content: &content,
+            unsubscribe_url: Some("https://example.com/unsubscribe?x=1"),
+        }];
+        let results = email_client.send_batch(&emails).await;
+        assert!(results[0].is_ok());
+
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            messages[0]["Headers"],
+            serde_json::json!([
+                {"Name": "List-Unsubscribe", "Value": "<https://example.com/unsubscribe?x=1>"},
+                {"Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click"},
+            ])
+        );
+    }
+}
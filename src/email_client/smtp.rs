@@ -0,0 +1,204 @@
+use super::{EmailClientError, EmailSender, OutgoingEmail};
+use crate::configuration::{SmtpSettings, SmtpTls};
+use crate::domain::SubscriberEmail;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use secrecy::ExposeSecret;
+use std::sync::Arc;
+
+pub struct SmtpEmailClient {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    sender: SubscriberEmail,
+}
+
+impl SmtpEmailClient {
+    pub fn new(
+        settings: &SmtpSettings,
+        sender: SubscriberEmail,
+        timeout: std::time::Duration,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let builder = match settings.tls {
+            SmtpTls::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host)
+            }
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)?,
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host)?,
+        };
+        let mut builder = builder.port(settings.port).timeout(Some(timeout));
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            builder = builder
+                .credentials(Credentials::new(
+                    username.clone(),
+                    password.expose_secret().clone(),
+                ))
+                .authentication(vec![Mechanism::Plain, Mechanism::Login]);
+        }
+        Ok(Self {
+            transport: builder.build(),
+            sender,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SmtpEmailClient {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<(), EmailClientError> {
+        let from: Mailbox = self
+            .sender
+            .as_ref()
+            .parse()
+            .map_err(|e| EmailClientError::UnexpectedError(Arc::new(e)))?;
+        let to: Mailbox = email
+            .recipient
+            .as_ref()
+            .parse()
+            .map_err(|e| EmailClientError::UnexpectedError(Arc::new(e)))?;
+        let mut builder = Message::builder().from(from).to(to).subject(email.subject);
+        for (name, value) in email.extra_headers() {
+            builder = builder.raw_header(HeaderValue::new(HeaderName::new_from_ascii_str(name), value));
+        }
+        let message = builder
+            .multipart(MultiPart::alternative_plain_html(
+                email.text_content.to_string(),
+                email.html_content.to_string(),
+            ))
+            .map_err(|e| EmailClientError::UnexpectedError(Arc::new(e)))?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for EmailClientError {
+    fn from(e: lettre::transport::smtp::Error) -> Self {
+        if e.is_timeout() {
+            EmailClientError::Timeout(Arc::new(e))
+        } else if let Some(code) = e.status() {
+            EmailClientError::SmtpReply {
+                code: code.into(),
+                message: e.to_string(),
+            }
+        } else {
+            EmailClientError::Connection(Arc::new(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{SmtpSettings, SmtpTls};
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClientError, EmailSender, SmtpEmailClient};
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A bare-bones SMTP server that accepts a single session and returns
+    /// every line the client sent. Replies to `RCPT TO` with `rcpt_reply`.
+    async fn smtp_stand_in(rcpt_reply: &'static str) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut transcript = Vec::new();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                transcript.push(line.clone());
+                let reply: &[u8] = if in_data {
+                    if line == "." {
+                        in_data = false;
+                        b"250 2.0.0 Ok: queued\r\n"
+                    } else {
+                        continue;
+                    }
+                } else {
+                    let command = line.to_uppercase();
+                    if command.starts_with("EHLO") {
+                        b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+                    } else if command.starts_with("AUTH") {
+                        b"235 2.7.0 Authentication successful\r\n"
+                    } else if command.starts_with("RCPT") {
+                        rcpt_reply.as_bytes()
+                    } else if command.starts_with("DATA") {
+                        in_data = true;
+                        b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                    } else if command.starts_with("QUIT") {
+                        writer.write_all(b"221 2.0.0 Bye\r\n").await.unwrap();
+                        break;
+                    } else {
+                        b"250 2.0.0 Ok\r\n"
+                    }
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            transcript
+        });
+        (port, handle)
+    }
+
+    fn smtp_client(port: u16) -> SmtpEmailClient {
+        let settings = SmtpSettings {
+            host: "127.0.0.1".into(),
+            port,
+            tls: SmtpTls::None,
+            username: Some("newsletter".into()),
+            password: Some(Secret::new("secret".into())),
+        };
+        SmtpEmailClient::new(
+            &settings,
+            SubscriberEmail::parse("sender@example.com".into()).unwrap(),
+            std::time::Duration::from_secs(5),
+        )
+        .unwrap()
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("recipient@example.com".into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_delivers_the_message_over_smtp() {
+        let (port, stand_in) = smtp_stand_in("250 2.1.5 Ok\r\n").await;
+
+        let outcome = smtp_client(port)
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await;
+        assert_ok!(outcome);
+
+        let transcript = stand_in.await.unwrap();
+        assert!(transcript.iter().any(|l| l.starts_with("AUTH PLAIN")));
+        assert!(transcript.iter().any(|l| l.contains("MAIL FROM:<sender@example.com>")));
+        assert!(transcript.iter().any(|l| l.contains("RCPT TO:<recipient@example.com>")));
+        assert!(transcript.iter().any(|l| l == "Subject: Welcome!"));
+    }
+
+    #[tokio::test]
+    async fn permanent_smtp_failures_are_not_retryable() {
+        let (port, _stand_in) = smtp_stand_in("550 5.1.1 No such user\r\n").await;
+
+        let outcome = smtp_client(port)
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await;
+        let e = assert_err!(outcome);
+        assert!(matches!(e, EmailClientError::SmtpReply { code: 550, .. }));
+        assert!(!e.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn transient_smtp_failures_are_retryable() {
+        let (port, _stand_in) = smtp_stand_in("451 4.3.0 Try again later\r\n").await;
+
+        let outcome = smtp_client(port)
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await;
+        let e = assert_err!(outcome);
+        assert!(matches!(e, EmailClientError::SmtpReply { code: 451, .. }));
+        assert!(e.is_retryable());
+    }
+}
@@ -0,0 +1,504 @@
+use crate::{
+    configuration::IssueDeliverySettings,
+    domain::{SubscriberEmail, SubscriptionStatus},
+    email_client::{EmailClientError, EmailSender, OutgoingEmail},
+    newsletter_template::{NewsletterTemplate, RecipientContext, RenderedContent},
+    routes::error_chain_fmt,
+    unsubscribe::UnsubscribeLinks,
+};
+use chrono::Utc;
+use rand::Rng;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+#[derive(thiserror::Error)]
+#[error("Failed to deliver newsletter issue to {0}.")]
+struct DeliveryError(String, #[source] EmailClientError);
+
+impl std::fmt::Debug for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl DeliveryError {
+    fn is_retryable(&self) -> bool {
+        self.1.is_retryable()
+    }
+}
+
+enum DeliveryOutcome {
+    Sent,
+    /// The error is not going to go away by retrying.
+    Failed(DeliveryError),
+    /// Still failing after `max_attempts`.
+    DeadLettered(DeliveryError),
+    Skipped(String),
+}
+
+impl DeliveryOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryOutcome::Sent => "sent",
+            DeliveryOutcome::Failed(_) => "failed",
+            DeliveryOutcome::DeadLettered(_) => "dead_letter",
+            DeliveryOutcome::Skipped(_) => "skipped",
+        }
+    }
+
+    fn error_chain(&self) -> Option<String> {
+        match self {
+            DeliveryOutcome::Sent => None,
+            DeliveryOutcome::Failed(e) | DeliveryOutcome::DeadLettered(e) => {
+                Some(format!("{:?}", e))
+            }
+            DeliveryOutcome::Skipped(reason) => Some(reason.clone()),
+        }
+    }
+}
+
+pub async fn worker_loop(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    settings: IssueDeliverySettings,
+    unsubscribe_links: UnsubscribeLinks,
+    send_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_execute_task(
+            &pool,
+            email_client.as_ref(),
+            &settings,
+            &unsubscribe_links,
+            send_timeout,
+        )
+        .await
+        {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(n_tasks=tracing::field::Empty), err)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    settings: &IssueDeliverySettings,
+    unsubscribe_links: &UnsubscribeLinks,
+    send_timeout: Duration,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let claim_duration = claim_duration(settings.batch_size, send_timeout);
+    let tasks = claim_tasks(pool, settings.batch_size, claim_duration).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    Span::current().record("n_tasks", display(tasks.len()));
+    let issues = get_issues(pool, &tasks).await?;
+    let templates: HashMap<_, _> = issues
+        .iter()
+        .map(|(issue_id, issue)| {
+            let template =
+                NewsletterTemplate::parse(&issue.title, &issue.html_content, &issue.text_content);
+            (*issue_id, template)
+        })
+        .collect();
+
+    let mut deliverable = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let subscriber_email = match SubscriberEmail::parse(task.subscriber_email.clone()) {
+            Ok(subscriber_email) => subscriber_email,
+            Err(e) => {
+                tracing::error!(
+                    newsletter_issue_id = %task.newsletter_issue_id,
+                    error.message = %e,
+                    "Skipping a confirmed subscriber. \
+                    Their stored contact details are invalid",
+                );
+                complete_task(pool, &task, DeliveryOutcome::Skipped(e)).await?;
+                continue;
+            }
+        };
+        let subscriber_id = match (task.subscriber_id, task.subscriber_status) {
+            (Some(subscriber_id), Some(SubscriptionStatus::Confirmed)) => subscriber_id,
+            (Some(_), Some(status)) => {
+                let reason = format!(
+                    "The subscriber left the confirmed state ({:?}) after the issue was published.",
+                    status
+                );
+                tracing::warn!(newsletter_issue_id = %task.newsletter_issue_id, "{}", reason);
+                complete_task(pool, &task, DeliveryOutcome::Skipped(reason)).await?;
+                continue;
+            }
+            _ => {
+                let reason = "The subscriber was deleted after the issue was published.";
+                tracing::warn!(newsletter_issue_id = %task.newsletter_issue_id, "{}", reason);
+                complete_task(pool, &task, DeliveryOutcome::Skipped(reason.into())).await?;
+                continue;
+            }
+        };
+        let unsubscribe_url = unsubscribe_links.url(subscriber_id);
+        match render_for(&templates[&task.newsletter_issue_id], &task, &unsubscribe_url) {
+            Ok(content) => deliverable.push((task, subscriber_email, content, unsubscribe_url)),
+            Err(e) => {
+                tracing::error!(
+                    newsletter_issue_id = %task.newsletter_issue_id,
+                    error.message = %e,
+                    "Skipping a confirmed subscriber. \
+                    The issue could not be rendered for them",
+                );
+                complete_task(pool, &task, DeliveryOutcome::Skipped(e)).await?;
+            }
+        }
+    }
+
+    let emails: Vec<_> = deliverable
+        .iter()
+        .map(|(task, subscriber_email, content, unsubscribe_url)| OutgoingEmail {
+            recipient: subscriber_email,
+            subject: &issues[&task.newsletter_issue_id].title,
+            html_content: &content.html,
+            text_content: &content.text,
+            unsubscribe_url: Some(unsubscribe_url),
+        })
+        .collect();
+    let results = email_client.send_batch(&emails).await;
+
+    // The emails are out: each outcome is recorded on its own, so that a
+    // failure to record one of them does not send the others again.
+    let mut n_unrecorded = 0;
+    for ((task, ..), result) in deliverable.into_iter().zip(results) {
+        if let Err(e) = record_result(pool, settings, &task, result).await {
+            tracing::error!(
+                newsletter_issue_id = %task.newsletter_issue_id,
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to record the outcome of a delivery",
+            );
+            n_unrecorded += 1;
+        }
+    }
+    if n_unrecorded > 0 {
+        anyhow::bail!("Failed to record the outcome of {} deliveries", n_unrecorded);
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+async fn record_result(
+    pool: &PgPool,
+    settings: &IssueDeliverySettings,
+    task: &Task,
+    result: Result<(), EmailClientError>,
+) -> Result<(), anyhow::Error> {
+    match result {
+        Ok(()) => complete_task(pool, task, DeliveryOutcome::Sent).await,
+        Err(e) => {
+            let e = DeliveryError(task.subscriber_email.clone(), e);
+            if e.is_retryable() && task.n_retries + 1 < settings.max_attempts {
+                let delay = retry_delay(settings, task.n_retries);
+                tracing::warn!(
+                    newsletter_issue_id = %task.newsletter_issue_id,
+                    n_retries = task.n_retries,
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to deliver issue to a confirmed subscriber. \
+                    Retrying in {:?}.",
+                    delay
+                );
+                reschedule_task(pool, task, delay).await
+            } else {
+                tracing::error!(
+                    newsletter_issue_id = %task.newsletter_issue_id,
+                    n_retries = task.n_retries,
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to deliver issue to a confirmed subscriber. \
+                    Giving up.",
+                );
+                let outcome = if e.is_retryable() {
+                    DeliveryOutcome::DeadLettered(e)
+                } else {
+                    DeliveryOutcome::Failed(e)
+                };
+                complete_task(pool, task, outcome).await
+            }
+        }
+    }
+}
+
+/// Queues one delivery task per confirmed subscriber.
+#[tracing::instrument(skip_all)]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email
+        )
+        SELECT $1, email
+        FROM subscriptions
+        WHERE status = 'confirmed'
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+struct Task {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    /// All missing if the subscriber was deleted after the task was queued.
+    subscriber_id: Option<Uuid>,
+    subscriber_name: Option<String>,
+    subscriber_status: Option<SubscriptionStatus>,
+    n_retries: i32,
+}
+
+fn render_for(
+    template: &Result<NewsletterTemplate, String>,
+    task: &Task,
+    unsubscribe_url: &str,
+) -> Result<RenderedContent, String> {
+    let template = template.as_ref().map_err(Clone::clone)?;
+    template.render(&RecipientContext {
+        subscriber_name: task.subscriber_name.as_deref().unwrap_or_default(),
+        subscriber_email: &task.subscriber_email,
+        unsubscribe_url,
+    })
+}
+
+/// Time for rendering and recording, on top of the sends themselves.
+const CLAIM_MARGIN: Duration = Duration::from_secs(60);
+
+/// How long claimed tasks are left alone by other workers: long enough for a
+/// provider without a batch API to send the whole batch one email at a time,
+/// each taking up to `send_timeout`. It only matters if the worker dies before
+/// recording how the deliveries went: the tasks are then picked up again once
+/// their claim runs out.
+fn claim_duration(batch_size: i64, send_timeout: Duration) -> Duration {
+    let batch_size = u32::try_from(batch_size).unwrap_or(u32::MAX);
+    send_timeout
+        .saturating_mul(batch_size)
+        .saturating_add(CLAIM_MARGIN)
+}
+
+/// Claims up to `batch_size` due tasks, so that no other worker picks them up
+/// while we send. The claim is committed straight away: no transaction stays
+/// open while emails go out.
+#[tracing::instrument(skip_all)]
+async fn claim_tasks(
+    pool: &PgPool,
+    batch_size: i64,
+    claim_duration: Duration,
+) -> Result<Vec<Task>, anyhow::Error> {
+    // `SKIP LOCKED` lets several workers claim tasks concurrently: each of
+    // them only sees the tasks nobody else is claiming.
+    let tasks = sqlx::query_as!(
+        Task,
+        r#"
+        WITH claimed AS (
+            UPDATE issue_delivery_queue
+            SET claimed_until = now() + make_interval(secs => $2)
+            WHERE (newsletter_issue_id, subscriber_email) IN (
+                SELECT newsletter_issue_id, subscriber_email
+                FROM issue_delivery_queue
+                WHERE
+                    execute_after <= now() AND
+                    (claimed_until IS NULL OR claimed_until <= now())
+                FOR UPDATE
+                SKIP LOCKED
+                LIMIT $1
+            )
+            RETURNING newsletter_issue_id, subscriber_email, n_retries
+        )
+        SELECT
+            c.newsletter_issue_id AS "newsletter_issue_id!",
+            c.subscriber_email AS "subscriber_email!",
+            s.id AS "subscriber_id?",
+            s.name AS "subscriber_name?",
+            s.status AS "subscriber_status?: SubscriptionStatus",
+            c.n_retries AS "n_retries!"
+        FROM claimed c
+        LEFT JOIN subscriptions s ON s.email = c.subscriber_email
+        "#,
+        batch_size,
+        claim_duration.as_secs_f64()
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(tasks)
+}
+
+/// Exponential backoff capped at `max_backoff_milliseconds`, plus up to
+/// `base_backoff_milliseconds` of jitter so retries do not arrive in lockstep.
+fn retry_delay(settings: &IssueDeliverySettings, n_retries: i32) -> Duration {
+    let exponent = n_retries.clamp(0, 31) as u32;
+    let backoff = settings
+        .base_backoff_milliseconds
+        .saturating_mul(2u64.saturating_pow(exponent))
+        .min(settings.max_backoff_milliseconds);
+    let jitter = rand::thread_rng().gen_range(0..=settings.base_backoff_milliseconds);
+    Duration::from_millis(backoff + jitter)
+}
+
+#[tracing::instrument(skip_all)]
+async fn reschedule_task(
+    pool: &PgPool,
+    task: &Task,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    let execute_after = Utc::now() + chrono::Duration::from_std(delay)?;
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            n_retries = n_retries + 1,
+            execute_after = $3,
+            claimed_until = NULL
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        execute_after
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Records how the delivery of `task` went and removes it from the queue.
+/// Deliveries that ran out of attempts stay in `issue_deliveries` as dead
+/// letters.
+#[tracing::instrument(skip_all)]
+async fn complete_task(
+    pool: &PgPool,
+    task: &Task,
+    outcome: DeliveryOutcome,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (
+            newsletter_issue_id,
+            subscriber_email,
+            outcome,
+            error_chain,
+            n_attempts,
+            completed_at
+        )
+        VALUES ($1, $2, $3, $4, $5, now())
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        outcome.as_str(),
+        outcome.error_chain(),
+        task.n_retries + 1
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+struct NewsletterIssue {
+    newsletter_issue_id: Uuid,
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issues(
+    pool: &PgPool,
+    tasks: &[Task],
+) -> Result<HashMap<Uuid, NewsletterIssue>, anyhow::Error> {
+    let issue_ids: Vec<Uuid> = tasks.iter().map(|t| t.newsletter_issue_id).collect();
+    let issues = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT newsletter_issue_id, title, text_content, html_content
+        FROM newsletter_issues
+        WHERE
+            newsletter_issue_id = ANY($1)
+        "#,
+        &issue_ids[..]
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(issues
+        .into_iter()
+        .map(|issue| (issue.newsletter_issue_id, issue))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{claim_duration, retry_delay};
+    use crate::configuration::IssueDeliverySettings;
+    use std::time::Duration;
+
+    fn settings() -> IssueDeliverySettings {
+        IssueDeliverySettings {
+            max_attempts: 5,
+            base_backoff_milliseconds: 1000,
+            max_backoff_milliseconds: 60_000,
+            batch_size: 500,
+        }
+    }
+
+    #[test]
+    fn retry_delay_grows_exponentially() {
+        for n_retries in 0..5 {
+            let delay = retry_delay(&settings(), n_retries);
+            let backoff = Duration::from_millis(1000 * 2u64.pow(n_retries as u32));
+            assert!(delay >= backoff);
+            assert!(delay <= backoff + Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let delay = retry_delay(&settings(), 1000);
+        assert!(delay >= Duration::from_millis(60_000));
+        assert!(delay <= Duration::from_millis(61_000));
+    }
+
+    #[test]
+    fn a_claim_outlasts_sending_the_batch_one_email_at_a_time() {
+        let send_timeout = Duration::from_secs(10);
+        assert!(claim_duration(500, send_timeout) > send_timeout * 500);
+    }
+}
@@ -1,6 +1,16 @@
+pub mod authentication;
 pub mod configuration;
 pub mod domain;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod markdown;
+pub mod newsletter_scheduler;
+pub mod newsletter_template;
+pub mod plain_text;
+pub mod problem;
 pub mod routes;
 pub mod startup;
+pub mod subscription_tokens;
 pub mod email_client;
 pub mod telemetry;
+pub mod unsubscribe;
@@ -0,0 +1,202 @@
+use crate::newsletter_template::KNOWN_VARIABLES;
+use pulldown_cmark::{html, Event, HeadingLevel, Parser, Tag};
+
+/// Renders Markdown to HTML, dropping anything unsafe (scripts, event
+/// handlers, ...) that the Markdown may have embedded.
+pub fn to_html(markdown: &str) -> String {
+    let (markdown, tags) = protect_template_tags(markdown);
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new(&markdown));
+    let html = ammonia::clean(&unsafe_html).replace('{', "&#123;");
+    restore_template_tags(html, &tags)
+}
+
+/// Renders Markdown to plain text meant to be read as is: headings are
+/// underlined and links become numbered footnotes.
+pub fn to_plain_text(markdown: &str) -> String {
+    let (markdown, tags) = protect_template_tags(markdown);
+    let mut text = String::new();
+    let mut footnotes = Vec::new();
+    let mut heading_start = 0;
+    // One entry per open list, holding the next number of ordered lists.
+    let mut lists: Vec<Option<u64>> = Vec::new();
+
+    for event in Parser::new(&markdown) {
+        match event {
+            Event::Start(Tag::Heading(..)) => heading_start = text.len(),
+            Event::End(Tag::Heading(level, ..)) => {
+                let width = text[heading_start..].chars().count();
+                let underline = if level == HeadingLevel::H1 { "=" } else { "-" };
+                text.push('\n');
+                text.push_str(&underline.repeat(width));
+                text.push_str("\n\n");
+            }
+            Event::End(Tag::Paragraph) => {
+                text.push_str(if lists.is_empty() { "\n\n" } else { "\n" });
+            }
+            Event::Start(Tag::List(first_number)) => {
+                start_line(&mut text);
+                lists.push(first_number);
+            }
+            Event::End(Tag::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    text.push('\n');
+                }
+            }
+            Event::Start(Tag::Item) => {
+                start_line(&mut text);
+                text.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        text.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => text.push_str("* "),
+                }
+            }
+            Event::End(Tag::Item) => start_line(&mut text),
+            Event::End(Tag::Link(_, url, _)) | Event::End(Tag::Image(_, url, _)) => {
+                footnotes.push(url);
+                text.push_str(&format!(" [{}]", footnotes.len()));
+            }
+            Event::End(Tag::CodeBlock(_)) => text.push('\n'),
+            Event::Text(content) | Event::Code(content) => text.push_str(&content),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Rule => text.push_str("----\n\n"),
+            _ => {}
+        }
+    }
+
+    let mut text = text.trim_end().to_owned();
+    if !footnotes.is_empty() {
+        text.push_str("\n\n");
+        for (i, url) in footnotes.iter().enumerate() {
+            text.push_str(&format!("[{}] {}\n", i + 1, url));
+        }
+    }
+    let text = text.trim_end().replace('{', r#"{{ "{" }}"#);
+    restore_template_tags(text, &tags)
+}
+
+/// Swaps template tags that print a known variable (`{{ unsubscribe_url }}`)
+/// for plain placeholders, so that Markdown leaves them alone. Left in place,
+/// a link to `{{ unsubscribe_url }}` would come out percent-encoded.
+///
+/// Placeholders skip the sanitizer, so any other tag (string literals,
+/// filters such as `safe`, `{% ... %}` blocks) is left to be rendered as
+/// text: its braces are escaped before the known tags are restored.
+fn protect_template_tags(markdown: &str) -> (String, Vec<String>) {
+    let mut protected = String::with_capacity(markdown.len());
+    let mut tags = Vec::new();
+    let mut rest = markdown;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start + 2..].find("}}") {
+            Some(end) => start + 2 + end + 2,
+            None => break,
+        };
+        let tag = &rest[start..end];
+        protected.push_str(&rest[..start]);
+        if KNOWN_VARIABLES.contains(&tag[2..tag.len() - 2].trim()) {
+            protected.push_str(&placeholder(tags.len()));
+            tags.push(tag.to_owned());
+        } else {
+            protected.push_str(tag);
+        }
+        rest = &rest[end..];
+    }
+    protected.push_str(rest);
+    (protected, tags)
+}
+
+fn restore_template_tags(mut rendered: String, tags: &[String]) -> String {
+    for (i, tag) in tags.iter().enumerate() {
+        rendered = rendered.replace(&placeholder(i), tag);
+    }
+    rendered
+}
+
+// Letters and digits only: Markdown, the sanitizer and URL encoding all pass
+// them through untouched. The trailing `x` keeps tag 1 from matching tag 10.
+fn placeholder(index: usize) -> String {
+    format!("templatetag{}x", index)
+}
+
+fn start_line(text: &mut String) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_html, to_plain_text};
+    use crate::newsletter_template::{NewsletterTemplate, RecipientContext};
+
+    #[test]
+    fn html_is_sanitized() {
+        let html = to_html("# Hello\n\n<script>alert('pwned')</script>\n\n[click](javascript:alert(1))");
+        assert!(html.contains("<h1>Hello</h1>"));
+        assert!(!html.contains("<script>"));
+        assert!(!html.contains("javascript:"));
+    }
+
+    #[test]
+    fn headings_are_underlined() {
+        assert_eq!(
+            to_plain_text("# Title\n\n## Section\n\nBody"),
+            "Title\n=====\n\nSection\n-------\n\nBody"
+        );
+    }
+
+    #[test]
+    fn links_become_footnotes() {
+        assert_eq!(
+            to_plain_text("Read [the post](https://example.com/post) and [more](https://example.com)."),
+            "Read the post [1] and more [2].\n\n[1] https://example.com/post\n[2] https://example.com"
+        );
+    }
+
+    #[test]
+    fn template_tags_survive_rendering() {
+        let markdown = "Hi {{ subscriber.name }}!\n\n[Unsubscribe]({{ unsubscribe_url }})";
+        let html = to_html(markdown);
+        assert!(html.contains("Hi {{ subscriber.name }}!"));
+        assert!(html.contains(r#"href="{{ unsubscribe_url }}""#));
+        assert_eq!(
+            to_plain_text(markdown),
+            "Hi {{ subscriber.name }}!\n\nUnsubscribe [1]\n\n[1] {{ unsubscribe_url }}"
+        );
+    }
+
+    #[test]
+    fn only_known_variables_skip_sanitization() {
+        for markdown in [
+            r#"[x]({{ "javascript:alert(1)" }})"#,
+            r#"[x]({{"javascript:alert(1)"}})"#,
+            r#"{{ "<script>alert(1)</script>" | safe }}"#,
+            r#"{{ "\u003cscript\u003ealert(1)\u003c/script\u003e" | safe }}"#,
+            "{% if true %}<script>alert(1)</script>{% endif %}",
+        ] {
+            let (html, text) = (to_html(markdown), to_plain_text(markdown));
+            let template = NewsletterTemplate::parse("Title", &html, &text).unwrap();
+            let rendered = template
+                .render(&RecipientContext {
+                    subscriber_name: "",
+                    subscriber_email: "",
+                    unsubscribe_url: "",
+                })
+                .unwrap();
+            assert!(!rendered.html.contains("<script>"), "{}", rendered.html);
+            assert!(!rendered.html.contains("href=\"javascript:"), "{}", rendered.html);
+        }
+    }
+
+    #[test]
+    fn lists_are_kept_readable() {
+        assert_eq!(
+            to_plain_text("Intro\n\n* one\n* two\n  1. nested\n  2. again\n\nOutro"),
+            "Intro\n\n* one\n* two\n  1. nested\n  2. again\n\nOutro"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use minijinja::{context, AutoEscape, Environment, UndefinedBehavior};
+use std::collections::HashSet;
+
+const HTML_TEMPLATE: &str = "html";
+const TEXT_TEMPLATE: &str = "text";
+
+/// Variables available to newsletter templates, in their dotted form.
+pub(crate) const KNOWN_VARIABLES: &[&str] = &[
+    "subscriber",
+    "subscriber.name",
+    "subscriber.email",
+    "unsubscribe_url",
+    "issue",
+    "issue.title",
+];
+
+/// What a newsletter template is rendered with for a single recipient.
+pub struct RecipientContext<'a> {
+    pub subscriber_name: &'a str,
+    pub subscriber_email: &'a str,
+    pub unsubscribe_url: &'a str,
+}
+
+pub struct RenderedContent {
+    pub html: String,
+    pub text: String,
+}
+
+/// The HTML and plain-text bodies of an issue, parsed once and rendered
+/// for as many recipients as needed.
+#[derive(Debug)]
+pub struct NewsletterTemplate<'source> {
+    env: Environment<'source>,
+    title: &'source str,
+}
+
+impl<'source> NewsletterTemplate<'source> {
+    /// Parses both bodies, failing with a message that points to the first
+    /// syntax error or unknown variable.
+    pub fn parse(title: &'source str, html: &'source str, text: &'source str) -> Result<Self, String> {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        env.set_auto_escape_callback(|name| match name {
+            HTML_TEMPLATE => AutoEscape::Html,
+            _ => AutoEscape::None,
+        });
+        for (name, source) in [(HTML_TEMPLATE, html), (TEXT_TEMPLATE, text)] {
+            env.add_template(name, source)
+                .map_err(|e| describe_error(name, &e))?;
+            let template = env.get_template(name).map_err(|e| describe_error(name, &e))?;
+            let known: HashSet<_> = KNOWN_VARIABLES.iter().copied().collect();
+            let mut unknown: Vec<_> = template
+                .undeclared_variables(true)
+                .into_iter()
+                .filter(|variable| !known.contains(variable.as_str()))
+                .collect();
+            unknown.sort();
+            if let Some(variable) = unknown.first() {
+                return Err(format!(
+                    "The {} template uses the unknown variable `{}`.",
+                    name, variable
+                ));
+            }
+        }
+        let template = Self { env, title };
+        // Catches what only shows up while rendering, e.g. `{{ subscriber.age }}`.
+        template.render(&RecipientContext {
+            subscriber_name: "",
+            subscriber_email: "",
+            unsubscribe_url: "",
+        })?;
+        Ok(template)
+    }
+
+    pub fn render(&self, recipient: &RecipientContext) -> Result<RenderedContent, String> {
+        let ctx = context! {
+            subscriber => context! {
+                name => recipient.subscriber_name,
+                email => recipient.subscriber_email,
+            },
+            unsubscribe_url => recipient.unsubscribe_url,
+            issue => context! { title => self.title },
+        };
+        let render = |name| {
+            self.env
+                .get_template(name)
+                .and_then(|template| template.render(&ctx))
+                .map_err(|e| describe_error(name, &e))
+        };
+        Ok(RenderedContent {
+            html: render(HTML_TEMPLATE)?,
+            text: render(TEXT_TEMPLATE)?,
+        })
+    }
+}
+
+fn describe_error(name: &str, e: &minijinja::Error) -> String {
+    let reason = e.detail().map(str::to_owned).unwrap_or_else(|| e.kind().to_string());
+    match e.line() {
+        Some(line) => format!("The {} template is invalid at line {}: {}.", name, line, reason),
+        None => format!("The {} template is invalid: {}.", name, reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NewsletterTemplate, RecipientContext};
+    use claim::{assert_err, assert_ok};
+
+    fn recipient() -> RecipientContext<'static> {
+        RecipientContext {
+            subscriber_name: "Ursula <3",
+            subscriber_email: "ursula@example.com",
+            unsubscribe_url: "https://example.com/unsubscribe",
+        }
+    }
+
+    #[test]
+    fn variables_are_rendered_per_recipient() {
+        let template = assert_ok!(NewsletterTemplate::parse(
+            "Issue #1",
+            "<p>Hi {{ subscriber.name }}, welcome to {{ issue.title }}</p>",
+            "Hi {{ subscriber.name }}, unsubscribe at {{ unsubscribe_url }}",
+        ));
+        let rendered = assert_ok!(template.render(&recipient()));
+        assert_eq!(rendered.html, "<p>Hi Ursula &lt;3, welcome to Issue #1</p>");
+        assert_eq!(
+            rendered.text,
+            "Hi Ursula <3, unsubscribe at https://example.com/unsubscribe"
+        );
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        let e = assert_err!(NewsletterTemplate::parse("Title", "{{ password }}", "Plain"));
+        assert!(e.contains("`password`"), "{}", e);
+        let e = assert_err!(NewsletterTemplate::parse("Title", "<p></p>", "{{ subscriber.age }}"));
+        assert!(e.contains("text"), "{}", e);
+    }
+
+    #[test]
+    fn syntax_errors_point_to_their_line() {
+        let e = assert_err!(NewsletterTemplate::parse(
+            "Title",
+            "<p>Hello</p>\n<p>{{ subscriber.name </p>",
+            "Plain"
+        ));
+        assert!(e.contains("html template is invalid at line 2"), "{}", e);
+    }
+}
@@ -0,0 +1,166 @@
+use html2text::render::text_renderer::{TaggedLine, TextDecorator};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Plain-text bodies are wrapped at 78 columns, as RFC 5322 recommends.
+const WIDTH: usize = 78;
+/// Stands in for link targets while the text is wrapped, so that long URLs
+/// are never split over two lines: they are put back once wrapping is done.
+const LINK_MARKER: char = '\u{FFFC}';
+
+/// Derives a readable plain-text body from an HTML one.
+/// Links are shown as `text (url)`, lists and tables are laid out as text.
+pub fn from_html(html: &str) -> Result<String, html2text::Error> {
+    let decorator = LinkDecorator::default();
+    let links = decorator.links.clone();
+    let text = html2text::config::with_decorator(decorator)
+        .allow_width_overflow()
+        .string_from_read(html.as_bytes(), WIDTH)?;
+    let mut text = text
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n");
+    for (i, url) in links.borrow().iter().enumerate() {
+        text = text.replace(&link_placeholder(i), url);
+    }
+    Ok(text.trim().to_owned())
+}
+
+fn link_placeholder(i: usize) -> String {
+    format!("{}{}{}", LINK_MARKER, i, LINK_MARKER)
+}
+
+#[derive(Default)]
+struct LinkDecorator {
+    /// Shared with the decorators of sub-blocks, so that every link
+    /// in the document gets its own placeholder.
+    links: Rc<RefCell<Vec<String>>>,
+    open_links: Vec<usize>,
+}
+
+impl TextDecorator for LinkDecorator {
+    type Annotation = ();
+
+    fn decorate_link_start(&mut self, url: &str) -> (String, Self::Annotation) {
+        let mut links = self.links.borrow_mut();
+        self.open_links.push(links.len());
+        links.push(url.to_owned());
+        (String::new(), ())
+    }
+
+    fn decorate_link_end(&mut self) -> String {
+        match self.open_links.pop() {
+            Some(i) => format!(" ({})", link_placeholder(i)),
+            None => String::new(),
+        }
+    }
+
+    fn decorate_em_start(&self) -> (String, Self::Annotation) {
+        ("*".into(), ())
+    }
+
+    fn decorate_em_end(&self) -> String {
+        "*".into()
+    }
+
+    fn decorate_strong_start(&self) -> (String, Self::Annotation) {
+        ("**".into(), ())
+    }
+
+    fn decorate_strong_end(&self) -> String {
+        "**".into()
+    }
+
+    fn decorate_strikeout_start(&self) -> (String, Self::Annotation) {
+        (String::new(), ())
+    }
+
+    fn decorate_strikeout_end(&self) -> String {
+        String::new()
+    }
+
+    fn decorate_code_start(&self) -> (String, Self::Annotation) {
+        ("`".into(), ())
+    }
+
+    fn decorate_code_end(&self) -> String {
+        "`".into()
+    }
+
+    fn decorate_preformat_first(&self) -> Self::Annotation {}
+
+    fn decorate_preformat_cont(&self) -> Self::Annotation {}
+
+    fn decorate_image(&mut self, _src: &str, title: &str) -> (String, Self::Annotation) {
+        (title.to_owned(), ())
+    }
+
+    fn header_prefix(&self, level: usize) -> String {
+        format!("{} ", "#".repeat(level))
+    }
+
+    fn quote_prefix(&self) -> String {
+        "> ".into()
+    }
+
+    fn unordered_item_prefix(&self) -> String {
+        "* ".into()
+    }
+
+    fn ordered_item_prefix(&self, i: i64) -> String {
+        format!("{}. ", i)
+    }
+
+    fn make_subblock_decorator(&self) -> Self {
+        Self {
+            links: self.links.clone(),
+            open_links: Vec::new(),
+        }
+    }
+
+    fn finalise(&mut self, _links: Vec<String>) -> Vec<TaggedLine<Self::Annotation>> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_html;
+
+    #[test]
+    fn links_are_shown_next_to_their_text() {
+        let text = from_html(r#"<p>Read <a href="https://example.com/post">the post</a>.</p>"#).unwrap();
+        assert_eq!(text, "Read the post (https://example.com/post).");
+    }
+
+    #[test]
+    fn lines_are_wrapped_at_78_columns() {
+        let text = from_html(&format!("<p>{}</p>", "lorem ipsum ".repeat(30))).unwrap();
+        assert!(text.lines().count() > 1);
+        assert!(text.lines().all(|line| line.chars().count() <= 78));
+    }
+
+    #[test]
+    fn long_urls_are_never_split() {
+        let url = format!("https://example.com/{}", "a".repeat(100));
+        let text = from_html(&format!(r#"<p>Click <a href="{}">here</a>.</p>"#, url)).unwrap();
+        assert!(text.contains(&url), "{}", text);
+    }
+
+    #[test]
+    fn lists_are_laid_out_as_text() {
+        let text = from_html("<ul><li>one</li><li>two</li></ul><ol><li>first</li></ol>").unwrap();
+        assert!(text.contains("* one\n* two"), "{}", text);
+        assert!(text.contains("1. first"), "{}", text);
+    }
+
+    #[test]
+    fn tables_keep_their_cells() {
+        let text = from_html(
+            "<table><tr><th>Plan</th><th>Price</th></tr><tr><td>Basic</td><td>5</td></tr></table>",
+        )
+        .unwrap();
+        assert!(text.contains("Plan") && text.contains("Basic"), "{}", text);
+    }
+}
@@ -0,0 +1,210 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{
+    InternalError, JsonPayloadError, PathError, QueryPayloadError, UrlencodedError,
+};
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use tracing_actix_web::RequestId;
+
+/// The kinds of problem the API reports. Their `type` URIs are part of the
+/// API: clients match on them, so they must never change.
+#[derive(Debug, Clone, Copy)]
+pub enum ProblemType {
+    InvalidRequest,
+    AuthenticationRequired,
+    InvalidConfirmationLink,
+    ExpiredConfirmationLink,
+    UsedConfirmationLink,
+    ConfirmationNotAllowed,
+    InvalidUnsubscribeLink,
+    NotFound,
+    UnsupportedMediaType,
+    InternalError,
+}
+
+impl ProblemType {
+    fn uri(self) -> &'static str {
+        match self {
+            ProblemType::InvalidRequest => "/problems/invalid-request",
+            ProblemType::AuthenticationRequired => "/problems/authentication-required",
+            ProblemType::InvalidConfirmationLink => "/problems/invalid-confirmation-link",
+            ProblemType::ExpiredConfirmationLink => "/problems/expired-confirmation-link",
+            ProblemType::UsedConfirmationLink => "/problems/used-confirmation-link",
+            ProblemType::ConfirmationNotAllowed => "/problems/confirmation-not-allowed",
+            ProblemType::InvalidUnsubscribeLink => "/problems/invalid-unsubscribe-link",
+            ProblemType::NotFound => "/problems/not-found",
+            ProblemType::UnsupportedMediaType => "/problems/unsupported-media-type",
+            ProblemType::InternalError => "/problems/internal-error",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            ProblemType::InvalidRequest => "The request is invalid.",
+            ProblemType::AuthenticationRequired => "Authentication is required.",
+            ProblemType::InvalidConfirmationLink => "The confirmation link is not valid.",
+            ProblemType::ExpiredConfirmationLink => "The confirmation link has expired.",
+            ProblemType::UsedConfirmationLink => "The confirmation link has already been used.",
+            ProblemType::ConfirmationNotAllowed => "The subscription cannot be confirmed.",
+            ProblemType::InvalidUnsubscribeLink => "The unsubscribe link is not valid.",
+            ProblemType::NotFound => "The resource does not exist.",
+            ProblemType::UnsupportedMediaType => "The body is in an unsupported format.",
+            ProblemType::InternalError => "Something went wrong on our side.",
+        }
+    }
+}
+
+/// An RFC 7807 `application/problem+json` document, the body of every error
+/// response. `detail` is shown to clients, so it must never carry internal
+/// error chains; those only go to the logs.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    type_uri: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    extensions: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Problem {
+    pub fn new(problem_type: ProblemType, status: StatusCode) -> Self {
+        Self {
+            type_uri: problem_type.uri(),
+            title: problem_type.title(),
+            status: status.as_u16(),
+            detail: None,
+            request_id: None,
+            extensions: serde_json::Map::new(),
+        }
+    }
+
+    pub fn internal_error() -> Self {
+        Self::new(ProblemType::InternalError, StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(ProblemType::NotFound, StatusCode::NOT_FOUND)
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Adds an extension member next to the standard ones.
+    pub fn extension(mut self, name: &str, value: impl serde::Serialize) -> Self {
+        let value = serde_json::to_value(value).expect("Problem extensions are valid JSON");
+        self.extensions.insert(name.into(), value);
+        self
+    }
+
+    /// Renders the problem into `response`, keeping its headers (e.g. a
+    /// `WWW-Authenticate` challenge). `add_request_id` fills in the request id
+    /// on the way out.
+    pub fn into_response<B>(self, response: HttpResponse<B>) -> HttpResponse {
+        let body = serde_json::to_string(&self).expect("Problems are valid JSON");
+        let mut response = response.set_body(body);
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        response.extensions_mut().insert(self);
+        response.map_into_boxed_body()
+    }
+
+    /// The response for this problem, with no other headers.
+    pub fn response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).expect("Problems have a valid status");
+        self.into_response(HttpResponse::new(status))
+    }
+}
+
+/// Reports what the `Json` extractor rejects, which would otherwise be a
+/// plain-text response.
+pub fn json_error(error: JsonPayloadError, _request: &HttpRequest) -> actix_web::Error {
+    let problem = match &error {
+        JsonPayloadError::ContentType => unsupported_media_type(),
+        JsonPayloadError::Deserialize(e) if e.is_data() => {
+            Problem::new(ProblemType::InvalidRequest, StatusCode::BAD_REQUEST)
+                .detail(format!("The body does not have the expected fields: {}.", e))
+        }
+        JsonPayloadError::Deserialize(e) => {
+            Problem::new(ProblemType::InvalidRequest, StatusCode::BAD_REQUEST)
+                .detail(format!("The body is not valid JSON: {}.", e))
+        }
+        _ => Problem::new(ProblemType::InvalidRequest, error.status_code()).detail(error.to_string()),
+    };
+    InternalError::from_response(error, problem.response()).into()
+}
+
+/// Same as `json_error`, for the `Form` extractor.
+pub fn form_error(error: UrlencodedError, _request: &HttpRequest) -> actix_web::Error {
+    let problem = match &error {
+        UrlencodedError::ContentType => unsupported_media_type(),
+        UrlencodedError::Parse(e) => {
+            Problem::new(ProblemType::InvalidRequest, StatusCode::BAD_REQUEST)
+                .detail(format!("The form does not have the expected fields: {}.", e))
+        }
+        _ => Problem::new(ProblemType::InvalidRequest, error.status_code()).detail(error.to_string()),
+    };
+    InternalError::from_response(error, problem.response()).into()
+}
+
+/// Same as `json_error`, for the `Query` extractor.
+pub fn query_error(error: QueryPayloadError, _request: &HttpRequest) -> actix_web::Error {
+    let problem = Problem::new(ProblemType::InvalidRequest, StatusCode::BAD_REQUEST).detail(format!(
+        "The query string does not have the expected parameters: {}.",
+        error_message(&error)
+    ));
+    InternalError::from_response(error, problem.response()).into()
+}
+
+/// Same as `json_error`, for the `Path` extractor. A path whose ids do not
+/// parse names no resource, hence a 404 like for unknown ids.
+pub fn path_error(error: PathError, _request: &HttpRequest) -> actix_web::Error {
+    let problem = Problem::not_found()
+        .detail(format!("There is nothing at this path: {}.", error_message(&error)));
+    InternalError::from_response(error, problem.response()).into()
+}
+
+/// The serde message, without the prefix actix adds to it.
+fn error_message(error: &(impl std::error::Error + 'static)) -> String {
+    match error.source() {
+        Some(source) => source.to_string(),
+        None => error.to_string(),
+    }
+}
+
+fn unsupported_media_type() -> Problem {
+    Problem::new(ProblemType::UnsupportedMediaType, StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .detail("The `Content-Type` of the body is not one this endpoint accepts.")
+}
+
+/// Adds the id of the request to problem responses, so that a client
+/// reporting one can be matched with our logs. Must run inside
+/// `TracingLogger`, which assigns the id.
+pub async fn add_request_id(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = request.extensions().get::<RequestId>().copied();
+    let response = next.call(request).await?;
+    let problem = response.response().extensions().get::<Problem>().cloned();
+    match (problem, request_id) {
+        (Some(mut problem), Some(request_id)) => {
+            problem.request_id = Some(request_id.to_string());
+            let (request, response) = response.into_parts();
+            let response = problem.into_response(response);
+            Ok(ServiceResponse::new(request, response).map_into_right_body())
+        }
+        _ => Ok(response.map_into_left_body()),
+    }
+}
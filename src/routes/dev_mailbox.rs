@@ -0,0 +1,159 @@
+use crate::problem::Problem;
+use crate::routes::error_chain_fmt;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use sqlx::PgPool;
+use std::fmt::Write;
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum MailboxError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for MailboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for MailboxError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            MailboxError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        Problem::internal_error().response()
+    }
+}
+
+#[tracing::instrument(name = "List development mailbox messages", skip(pool))]
+pub async fn list_mailbox_messages(pool: web::Data<PgPool>) -> Result<HttpResponse, MailboxError> {
+    let messages = sqlx::query!(
+        r#"
+        SELECT message_id, recipient, subject, received_at
+        FROM mailbox_messages
+        ORDER BY received_at DESC
+        LIMIT 100
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to retrieve mailbox messages.")?;
+
+    let mut rows = String::new();
+    for m in messages {
+        writeln!(
+            rows,
+            r#"<tr><td>{}</td><td>{}</td><td><a href="/dev/mailbox/{}">{}</a></td></tr>"#,
+            m.received_at.to_rfc3339(),
+            escape_html(&m.recipient),
+            m.message_id,
+            escape_html(&m.subject),
+        )
+        .unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta http-equiv="content-type" content="text/html; charset=utf-8"><title>Mailbox</title></head>
+<body>
+<h1>Mailbox</h1>
+<table>
+<tr><th>Received at</th><th>To</th><th>Subject</th></tr>
+{}</table>
+</body>
+</html>"#,
+            rows
+        )))
+}
+
+#[tracing::instrument(name = "Show a development mailbox message", skip(pool))]
+pub async fn show_mailbox_message(
+    message_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, MailboxError> {
+    struct Row {
+        recipient: String,
+        subject: String,
+        html_content: String,
+        text_content: String,
+        headers: serde_json::Value,
+        received_at: DateTime<Utc>,
+    }
+    let message = sqlx::query_as!(
+        Row,
+        r#"
+        SELECT recipient, subject, html_content, text_content, headers, received_at
+        FROM mailbox_messages
+        WHERE message_id = $1
+        "#,
+        message_id.into_inner()
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to retrieve a mailbox message.")?;
+    let message = match message {
+        Some(message) => message,
+        None => return Ok(Problem::not_found().response()),
+    };
+
+    let mut headers = String::new();
+    for header in message.headers.as_array().into_iter().flatten() {
+        writeln!(
+            headers,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            escape_html(header["name"].as_str().unwrap_or_default()),
+            escape_html(header["value"].as_str().unwrap_or_default()),
+        )
+        .unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta http-equiv="content-type" content="text/html; charset=utf-8"><title>{subject}</title></head>
+<body>
+<p><a href="/dev/mailbox">&larr; Mailbox</a></p>
+<h1>{subject}</h1>
+<p>To {recipient}, received at {received_at}</p>
+<table>
+{headers}</table>
+<h2>HTML</h2>
+<iframe sandbox="" style="width: 100%; height: 400px;" srcdoc="{html}"></iframe>
+<h2>Text</h2>
+<pre>{text}</pre>
+</body>
+</html>"#,
+            subject = escape_html(&message.subject),
+            recipient = escape_html(&message.recipient),
+            received_at = message.received_at.to_rfc3339(),
+            headers = headers,
+            html = escape_html(&message.html_content),
+            text = escape_html(&message.text_content),
+        )))
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
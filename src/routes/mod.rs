@@ -1,9 +1,19 @@
+mod dev_mailbox;
 mod health_check;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_resend_confirmation;
+mod subscriptions_unsubscribe;
+mod newsletter_issues;
 mod newsletters;
+mod scheduled_issues;
 
+pub use dev_mailbox::*;
 pub use health_check::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
-pub use newsletters::*;
\ No newline at end of file
+pub use subscriptions_resend_confirmation::*;
+pub use subscriptions_unsubscribe::*;
+pub use newsletter_issues::*;
+pub use newsletters::*;
+pub use scheduled_issues::*;
\ No newline at end of file
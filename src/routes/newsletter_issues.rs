@@ -0,0 +1,297 @@
+use crate::{
+    authentication::{authenticate, basic_auth_challenge, AuthError},
+    problem::{Problem, ProblemType},
+    routes::error_chain_fmt,
+};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const DEFAULT_PER_PAGE: i64 = 20;
+const MAX_PER_PAGE: i64 = 100;
+
+#[derive(thiserror::Error)]
+pub enum IssuesError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("Authentication failed.")]
+    AuthError(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for IssuesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for IssuesError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            IssuesError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            IssuesError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            IssuesError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            IssuesError::ValidationError(message) => {
+                Problem::new(ProblemType::InvalidRequest, self.status_code())
+                    .detail(message)
+                    .response()
+            }
+            IssuesError::UnexpectedError(_) => Problem::internal_error().response(),
+            IssuesError::AuthError(_) => {
+                Problem::new(ProblemType::AuthenticationRequired, self.status_code())
+                    .into_response(basic_auth_challenge())
+            }
+        }
+    }
+}
+
+impl From<AuthError> for IssuesError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::InvalidCredentials(_) => IssuesError::AuthError(e.into()),
+            AuthError::UnexpectedError(_) => IssuesError::UnexpectedError(e.into()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct Pagination {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+impl Pagination {
+    fn limit_and_offset(&self) -> Result<(i64, i64), IssuesError> {
+        let page = self.page.unwrap_or(1);
+        let per_page = self.per_page.unwrap_or(DEFAULT_PER_PAGE);
+        if page < 1 {
+            return Err(IssuesError::ValidationError(
+                "`page` must be greater than zero.".into(),
+            ));
+        }
+        if !(1..=MAX_PER_PAGE).contains(&per_page) {
+            return Err(IssuesError::ValidationError(format!(
+                "`per_page` must be between 1 and {}.",
+                MAX_PER_PAGE
+            )));
+        }
+        let offset = (page - 1).checked_mul(per_page).ok_or_else(|| {
+            IssuesError::ValidationError("`page` is too large.".into())
+        })?;
+        Ok((per_page, offset))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct IssueList {
+    issues: Vec<IssueListItem>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+#[derive(serde::Serialize)]
+struct IssueListItem {
+    newsletter_issue_id: Uuid,
+    title: String,
+    created_at: DateTime<Utc>,
+    published_at: DateTime<Utc>,
+    author: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct Issue {
+    newsletter_issue_id: Uuid,
+    title: String,
+    html_content: String,
+    text_content: String,
+    markdown_content: Option<String>,
+    created_at: DateTime<Utc>,
+    published_at: DateTime<Utc>,
+    author: Option<String>,
+    delivery: DeliverySummary,
+}
+
+#[derive(serde::Serialize)]
+struct DeliverySummary {
+    sent: i64,
+    failed: i64,
+    dead_letter: i64,
+    skipped: i64,
+    pending: i64,
+}
+
+#[tracing::instrument(
+    name = "List newsletter issues",
+    skip(pagination, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn list_issues(
+    pagination: web::Query<Pagination>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, IssuesError> {
+    authenticate(&request, &pool).await?;
+    let (limit, offset) = pagination.limit_and_offset()?;
+    let issues = sqlx::query_as!(
+        IssueListItem,
+        r#"
+        SELECT
+            i.newsletter_issue_id,
+            i.title,
+            i.created_at,
+            i.published_at,
+            u.username AS "author?"
+        FROM newsletter_issues i
+        LEFT JOIN users u ON u.user_id = i.author_id
+        WHERE NOT EXISTS (
+            SELECT 1 FROM scheduled_newsletter_issues s
+            WHERE s.newsletter_issue_id = i.newsletter_issue_id
+        )
+        ORDER BY i.published_at DESC, i.newsletter_issue_id
+        LIMIT $1
+        OFFSET $2
+        "#,
+        limit,
+        offset
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to retrieve newsletter issues.")?;
+    let total = sqlx::query!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM newsletter_issues i
+        WHERE NOT EXISTS (
+            SELECT 1 FROM scheduled_newsletter_issues s
+            WHERE s.newsletter_issue_id = i.newsletter_issue_id
+        )
+        "#
+    )
+    .fetch_one(pool.get_ref())
+    .await
+    .context("Failed to count newsletter issues.")?
+    .count;
+    Ok(HttpResponse::Ok().json(IssueList {
+        issues,
+        page: offset / limit + 1,
+        per_page: limit,
+        total,
+    }))
+}
+
+#[tracing::instrument(
+    name = "Get a newsletter issue",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, IssuesError> {
+    authenticate(&request, &pool).await?;
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let issue = sqlx::query!(
+        r#"
+        SELECT
+            i.newsletter_issue_id,
+            i.title,
+            i.html_content,
+            i.text_content,
+            i.markdown_content,
+            i.created_at,
+            i.published_at,
+            u.username AS "author?"
+        FROM newsletter_issues i
+        LEFT JOIN users u ON u.user_id = i.author_id
+        WHERE i.newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to retrieve a newsletter issue.")?;
+    let issue = match issue {
+        Some(issue) => issue,
+        None => {
+            return Ok(Problem::not_found()
+                .detail("There is no newsletter issue with this id.")
+                .response())
+        }
+    };
+    let delivery = fetch_delivery_summary(&pool, newsletter_issue_id)
+        .await
+        .context("Failed to retrieve the delivery summary of a newsletter issue.")?
+        .context("The newsletter issue disappeared while we were reading it.")?;
+    Ok(HttpResponse::Ok().json(Issue {
+        newsletter_issue_id: issue.newsletter_issue_id,
+        title: issue.title,
+        html_content: issue.html_content,
+        text_content: issue.text_content,
+        markdown_content: issue.markdown_content,
+        created_at: issue.created_at,
+        published_at: issue.published_at,
+        author: issue.author,
+        delivery,
+    }))
+}
+
+#[tracing::instrument(
+    name = "Get newsletter issue delivery summary",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_delivery_summary(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, IssuesError> {
+    authenticate(&request, &pool).await?;
+    let summary = fetch_delivery_summary(&pool, newsletter_issue_id.into_inner())
+        .await
+        .context("Failed to retrieve the delivery summary of a newsletter issue.")?;
+    match summary {
+        Some(summary) => Ok(HttpResponse::Ok().json(summary)),
+        None => Ok(Problem::not_found()
+            .detail("There is no newsletter issue with this id.")
+            .response()),
+    }
+}
+
+/// Counts the deliveries of an issue by outcome, or `None` if the issue does not exist.
+async fn fetch_delivery_summary(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<DeliverySummary>, sqlx::Error> {
+    sqlx::query_as!(
+        DeliverySummary,
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM issue_deliveries d
+             WHERE d.newsletter_issue_id = i.newsletter_issue_id AND d.outcome = 'sent') AS "sent!",
+            (SELECT COUNT(*) FROM issue_deliveries d
+             WHERE d.newsletter_issue_id = i.newsletter_issue_id AND d.outcome = 'failed') AS "failed!",
+            (SELECT COUNT(*) FROM issue_deliveries d
+             WHERE d.newsletter_issue_id = i.newsletter_issue_id AND d.outcome = 'dead_letter') AS "dead_letter!",
+            (SELECT COUNT(*) FROM issue_deliveries d
+             WHERE d.newsletter_issue_id = i.newsletter_issue_id AND d.outcome = 'skipped') AS "skipped!",
+            (SELECT COUNT(*) FROM issue_delivery_queue q
+             WHERE q.newsletter_issue_id = i.newsletter_issue_id) AS "pending!"
+        FROM newsletter_issues i
+        WHERE i.newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool)
+    .await
+}
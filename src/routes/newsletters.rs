@@ -1,22 +1,106 @@
-use crate::{routes::error_chain_fmt, email_client::{EmailClient}, domain::SubscriberEmail};
-use actix_web::{web, HttpResponse, ResponseError};
+use crate::{
+    authentication::{authenticate, basic_auth_challenge, AuthError},
+    domain::SubscriberEmail,
+    email_client::{EmailSender, OutgoingEmail},
+    idempotency::{save_response, try_processing, IdempotencyKey, NextAction},
+    issue_delivery_worker::enqueue_delivery_tasks,
+    markdown, plain_text,
+    newsletter_template::{NewsletterTemplate, RecipientContext},
+    problem::{Problem, ProblemType},
+    routes::error_chain_fmt,
+    startup::SubscriptionSettings,
+    unsubscribe::UnsubscribeLinks,
+};
+use actix_web::{
+    http::header::HeaderMap,
+    web, HttpRequest, HttpResponse, ResponseError,
+};
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct BodyData {
     title: String,
     content: Content,
+    /// Holds the issue back until then instead of sending it right away.
+    send_at: Option<DateTime<Utc>>,
 }
+
+#[derive(serde::Deserialize)]
+pub struct PublishOptions {
+    /// Reports what publishing would do without storing or sending anything.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// A newsletter issue together with the addresses its preview goes to.
+#[derive(serde::Deserialize)]
+pub struct PreviewData {
+    #[serde(flatten)]
+    newsletter: BodyData,
+    recipients: Vec<String>,
+}
+
+/// Either an `html` body, optionally with a hand-written `text` one,
+/// or `markdown` to render both from.
 #[derive(serde::Deserialize)]
 pub struct Content {
+    html: Option<String>,
+    text: Option<String>,
+    markdown: Option<String>,
+}
+
+/// The bodies an issue is stored and sent with.
+struct IssueContent {
     html: String,
     text: String,
+    markdown: Option<String>,
+}
+
+impl IssueContent {
+    fn parse(content: &Content) -> Result<Self, PublishError> {
+        match (&content.markdown, &content.html, &content.text) {
+            (Some(md), None, None) => Ok(Self {
+                html: markdown::to_html(md),
+                text: markdown::to_plain_text(md),
+                markdown: Some(md.clone()),
+            }),
+            (None, Some(html), text) => {
+                let text = match text {
+                    Some(text) => text.clone(),
+                    None => plain_text::from_html(html)
+                        .context("Failed to derive a plain-text body from the HTML one.")?,
+                };
+                Ok(Self {
+                    html: html.clone(),
+                    text,
+                    markdown: None,
+                })
+            }
+            (Some(_), _, _) => Err(PublishError::ValidationError(
+                "`markdown` cannot be combined with `html` or `text`.".into(),
+            )),
+            (None, None, _) => Err(PublishError::ValidationError(
+                "`html` is required unless `markdown` is provided.".into(),
+            )),
+        }
+    }
+
+    fn template<'a>(&'a self, title: &'a str) -> Result<NewsletterTemplate<'a>, PublishError> {
+        NewsletterTemplate::parse(title, &self.html, &self.text)
+            .map_err(PublishError::ValidationError)
+    }
 }
 
 #[derive(thiserror::Error)]
 pub enum PublishError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("Authentication failed.")]
+    AuthError(#[source] anyhow::Error),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -30,67 +114,335 @@ impl std::fmt::Debug for PublishError {
 impl ResponseError for PublishError {
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
+            PublishError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            PublishError::AuthError(_) => StatusCode::UNAUTHORIZED,
             PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PublishError::ValidationError(message) => {
+                Problem::new(ProblemType::InvalidRequest, self.status_code())
+                    .detail(message)
+                    .response()
+            }
+            PublishError::UnexpectedError(_) => Problem::internal_error().response(),
+            PublishError::AuthError(_) => {
+                Problem::new(ProblemType::AuthenticationRequired, self.status_code())
+                    .into_response(basic_auth_challenge())
+            }
+        }
+    }
 }
+
+impl From<AuthError> for PublishError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::InvalidCredentials(_) => PublishError::AuthError(e.into()),
+            AuthError::UnexpectedError(_) => PublishError::UnexpectedError(e.into()),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(body, options, pool, settings, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
 pub async fn publish_newsletter(
-     body: web::Json<BodyData>,
+    body: web::Json<BodyData>,
+    options: web::Query<PublishOptions>,
     pool: web::Data<PgPool>,
-     email_client: web::Data<EmailClient>,
-    ) -> Result<HttpResponse, PublishError> {
-    let subscribers = get_confirmed_subscribers(&pool).await?;
-    for subscriber in subscribers {
-        email_client.send_email(
-            &subscriber.email, 
-            &body.title,
-             &body.content.html,
-              &body.content.text,
-            )
+    settings: web::Data<SubscriptionSettings>,
+    request: HttpRequest,
+) -> Result<HttpResponse, PublishError> {
+    let user_id = authenticate(&request, &pool).await?;
+    if let Some(send_at) = body.send_at {
+        validate_send_at(send_at).map_err(PublishError::ValidationError)?;
+    }
+    let content = IssueContent::parse(&body.content)?;
+    let template = content.template(&body.title)?;
+    if options.dry_run {
+        let report = dry_run(&pool, &settings.unsubscribe_links, &body.title, &template)
             .await
-            .with_context(||{
-                format!("Failed to send newsletter to {}", &subscriber.email.0)
-            })?;
+            .context("Failed to simulate the publication of a newsletter issue.")?;
+        return Ok(HttpResponse::Ok().json(report));
     }
 
-    Ok(HttpResponse::Ok().finish())
+    let idempotency_key = get_idempotency_key(request.headers())?;
+    let mut transaction = match &idempotency_key {
+        Some(idempotency_key) => match try_processing(&pool, idempotency_key, user_id).await? {
+            NextAction::StartProcessing(transaction) => *transaction,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?,
+    };
+
+    let issue_id = insert_newsletter_issue(
+        &mut transaction,
+        user_id,
+        &body.title,
+        &content,
+        body.send_at,
+    )
+    .await
+    .context("Failed to store newsletter issue details")?;
+    match body.send_at {
+        Some(send_at) => schedule_newsletter_issue(&mut transaction, issue_id, send_at)
+            .await
+            .context("Failed to schedule newsletter issue")?,
+        None => enqueue_delivery_tasks(&mut transaction, issue_id)
+            .await
+            .context("Failed to enqueue delivery tasks")?,
+    }
+
+    let response = HttpResponse::Accepted().json(PublishedIssue {
+        newsletter_issue_id: issue_id,
+        send_at: body.send_at,
+    });
+    let response = match idempotency_key {
+        Some(idempotency_key) => {
+            save_response(transaction, &idempotency_key, user_id, response).await?
+        }
+        None => {
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit transaction to publish a newsletter issue.")?;
+            response
+        }
+    };
+    Ok(response)
 }
 
-pub struct ConfirmedSubscriber {
-    email: SubscriberEmail,
+#[derive(serde::Serialize)]
+struct DryRunReport {
+    recipients: usize,
+    invalid_recipients: usize,
+    sample: Option<SampleEmail>,
 }
 
-#[tracing::instrument(name = "Get confirmed subscribers", skip(pool))]
-async fn get_confirmed_subscribers(
+/// The email the first valid recipient would get.
+#[derive(serde::Serialize)]
+struct SampleEmail {
+    recipient: String,
+    subject: String,
+    html_content: String,
+    text_content: String,
+}
+
+/// Selects recipients the same way the delivery worker does, skipping the
+/// addresses it would refuse to send to.
+#[tracing::instrument(skip_all)]
+async fn dry_run(
     pool: &PgPool,
-) -> Result<Vec<ConfirmedSubscriber>, anyhow::Error> {
-    struct Row {
-        email: String,
-    }
-    let rows:  Vec<Row> = sqlx::query_as!(
-        Row,
+    unsubscribe_links: &UnsubscribeLinks,
+    title: &str,
+    template: &NewsletterTemplate<'_>,
+) -> Result<DryRunReport, anyhow::Error> {
+    let rows = sqlx::query!(
         r#"
-SELECT email
-FROM subscriptions
-WHERE status = 'confirmed'
-"#,
+        SELECT id, email, name
+        FROM subscriptions
+        WHERE status = 'confirmed'
+        ORDER BY email
+        "#,
     )
     .fetch_all(pool)
     .await?;
-
-    let confirmed_subscribes = rows
-    .into_iter()
-    .filter_map(|r| match SubscriberEmail::parse(r.email){
-        Ok(email) => Some(ConfirmedSubscriber{email}),
-        Err(error) => {
-            tracing::warn!(
-                "A confirmed subscriber is using an invalid email address.\n{}.",
-error
-            );
-            None
+    let mut recipients = Vec::with_capacity(rows.len());
+    let mut invalid_recipients = 0;
+    for row in rows {
+        match SubscriberEmail::parse(row.email) {
+            Ok(recipient) => recipients.push((recipient, row.id, row.name)),
+            Err(e) => {
+                tracing::warn!(
+                    error.message = %e,
+                    "A confirmed subscriber would be skipped. \
+                    Their stored contact details are invalid",
+                );
+                invalid_recipients += 1;
+            }
+        }
+    }
+    let sample = match recipients.first() {
+        Some((recipient, subscriber_id, name)) => {
+            let content = template
+                .render(&RecipientContext {
+                    subscriber_name: name,
+                    subscriber_email: recipient.as_ref(),
+                    unsubscribe_url: &unsubscribe_links.url(*subscriber_id),
+                })
+                .map_err(anyhow::Error::msg)?;
+            Some(SampleEmail {
+                recipient: recipient.as_ref().to_owned(),
+                subject: title.to_owned(),
+                html_content: content.html,
+                text_content: content.text,
+            })
         }
+        None => None,
+    };
+    Ok(DryRunReport {
+        recipients: recipients.len(),
+        invalid_recipients,
+        sample,
     })
-    .collect();
-    
-    Ok(confirmed_subscribes)
+}
+
+const MAX_PREVIEW_RECIPIENTS: usize = 10;
+/// Preview recipients are not subscribers, so they get a stand-in name
+/// and an unsubscribe link that goes nowhere.
+const PREVIEW_SUBSCRIBER_NAME: &str = "Test Subscriber";
+const PREVIEW_UNSUBSCRIBE_URL: &str = "#unsubscribe";
+
+#[tracing::instrument(
+    name = "Send a newsletter preview",
+    skip(body, pool, email_client, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn send_newsletter_preview(
+    body: web::Json<PreviewData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    request: HttpRequest,
+) -> Result<HttpResponse, PublishError> {
+    authenticate(&request, &pool).await?;
+    if body.recipients.is_empty() || body.recipients.len() > MAX_PREVIEW_RECIPIENTS {
+        return Err(PublishError::ValidationError(format!(
+            "A preview needs between 1 and {} recipients.",
+            MAX_PREVIEW_RECIPIENTS
+        )));
+    }
+    let recipients = body
+        .recipients
+        .iter()
+        .map(|recipient| SubscriberEmail::parse(recipient.clone()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(PublishError::ValidationError)?;
+
+    let newsletter = &body.newsletter;
+    let content = IssueContent::parse(&newsletter.content)?;
+    let template = content.template(&newsletter.title)?;
+    let subject = format!("[TEST] {}", newsletter.title);
+    let contents = recipients
+        .iter()
+        .map(|recipient| {
+            template.render(&RecipientContext {
+                subscriber_name: PREVIEW_SUBSCRIBER_NAME,
+                subscriber_email: recipient.as_ref(),
+                unsubscribe_url: PREVIEW_UNSUBSCRIBE_URL,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(PublishError::ValidationError)?;
+    let emails: Vec<_> = recipients
+        .iter()
+        .zip(&contents)
+        .map(|(recipient, content)| OutgoingEmail {
+            recipient,
+            subject: &subject,
+            html_content: &content.html,
+            text_content: &content.text,
+            unsubscribe_url: None,
+        })
+        .collect();
+    let results = email_client.send_batch(&emails).await;
+    for (recipient, result) in recipients.iter().zip(results) {
+        result.with_context(|| {
+            format!("Failed to send a newsletter preview to {}", recipient.as_ref())
+        })?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(serde::Serialize)]
+struct PublishedIssue {
+    newsletter_issue_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    send_at: Option<DateTime<Utc>>,
+}
+
+pub(crate) fn validate_send_at(send_at: DateTime<Utc>) -> Result<(), String> {
+    if send_at <= Utc::now() {
+        return Err("`send_at` must be in the future.".into());
+    }
+    Ok(())
+}
+
+fn get_idempotency_key(headers: &HeaderMap) -> Result<Option<IdempotencyKey>, PublishError> {
+    let header_value = match headers.get("Idempotency-Key") {
+        Some(header_value) => header_value,
+        None => return Ok(None),
+    };
+    let idempotency_key = header_value
+        .to_str()
+        .map_err(|_| {
+            PublishError::ValidationError(
+                "The 'Idempotency-Key' header was not a valid UTF8 string.".into(),
+            )
+        })?
+        .to_owned()
+        .try_into()
+        .map_err(|e: anyhow::Error| PublishError::ValidationError(e.to_string()))?;
+    Ok(Some(idempotency_key))
+}
+
+#[tracing::instrument(skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    author_id: Uuid,
+    title: &str,
+    content: &IssueContent,
+    send_at: Option<DateTime<Utc>>,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            markdown_content,
+            created_at,
+            published_at,
+            author_id
+        )
+        VALUES ($1, $2, $3, $4, $5, now(), COALESCE($6, now()), $7)
+        "#,
+        newsletter_issue_id,
+        title,
+        content.text,
+        content.html,
+        content.markdown,
+        send_at,
+        author_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(newsletter_issue_id)
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    send_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO scheduled_newsletter_issues (newsletter_issue_id, send_at)
+        VALUES ($1, $2)
+        "#,
+        newsletter_issue_id,
+        send_at
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
 }
@@ -0,0 +1,162 @@
+use crate::{authentication::authenticate, problem::Problem, routes::IssuesError};
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::newsletters::validate_send_at;
+
+#[derive(serde::Serialize)]
+struct ScheduledIssueList {
+    issues: Vec<ScheduledIssue>,
+}
+
+#[derive(serde::Serialize)]
+struct ScheduledIssue {
+    newsletter_issue_id: Uuid,
+    title: String,
+    send_at: DateTime<Utc>,
+    author: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RescheduleData {
+    send_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(
+    name = "List scheduled newsletter issues",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn list_scheduled_issues(
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, IssuesError> {
+    authenticate(&request, &pool).await?;
+    let issues = sqlx::query_as!(
+        ScheduledIssue,
+        r#"
+        SELECT
+            i.newsletter_issue_id,
+            i.title,
+            s.send_at,
+            u.username AS "author?"
+        FROM scheduled_newsletter_issues s
+        JOIN newsletter_issues i ON i.newsletter_issue_id = s.newsletter_issue_id
+        LEFT JOIN users u ON u.user_id = i.author_id
+        ORDER BY s.send_at, i.newsletter_issue_id
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to retrieve scheduled newsletter issues.")?;
+    Ok(HttpResponse::Ok().json(ScheduledIssueList { issues }))
+}
+
+#[tracing::instrument(
+    name = "Reschedule a newsletter issue",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn reschedule_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<RescheduleData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, IssuesError> {
+    authenticate(&request, &pool).await?;
+    validate_send_at(body.send_at).map_err(IssuesError::ValidationError)?;
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let rescheduled = sqlx::query!(
+        r#"
+        UPDATE scheduled_newsletter_issues
+        SET send_at = $2
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        body.send_at
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to reschedule a newsletter issue.")?
+    .rows_affected();
+    // Issues that were already released are not pending anymore.
+    if rescheduled == 0 {
+        return Ok(Problem::not_found()
+            .detail("There is no pending issue with this id.")
+            .response());
+    }
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET published_at = $2
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        body.send_at
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update the publication date of a newsletter issue.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit transaction to reschedule a newsletter issue.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(
+    name = "Cancel a scheduled newsletter issue",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn cancel_scheduled_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, IssuesError> {
+    authenticate(&request, &pool).await?;
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let cancelled = sqlx::query!(
+        r#"
+        DELETE FROM scheduled_newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to cancel a scheduled newsletter issue.")?
+    .rows_affected();
+    if cancelled == 0 {
+        return Ok(Problem::not_found()
+            .detail("There is no pending issue with this id.")
+            .response());
+    }
+    // Nothing went out yet: a cancelled issue was never published.
+    sqlx::query!(
+        r#"
+        DELETE FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete a cancelled newsletter issue.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit transaction to cancel a newsletter issue.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
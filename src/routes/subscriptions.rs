@@ -1,19 +1,35 @@
 use crate::{
-    domain::{NewSubscriber, SubscriberEmail, SubscriberName},
-    email_client::EmailClient, startup::ApplicationUrl,
+    configuration::OptIn,
+    domain::{
+        InvalidTransition, NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus,
+    },
+    email_client::{EmailClientError, EmailSender, OutgoingEmail},
+    plain_text,
+    problem::{Problem, ProblemType},
+    routes::confirmation_recently_sent,
+    startup::SubscriptionSettings,
+    subscription_tokens::SubscriptionTokenHasher,
 };
-use actix_web::{web, HttpResponse, Responder, ResponseError};
+use actix_web::{error::EitherExtractError, web, Either, HttpResponse, ResponseError};
 use anyhow::Context;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use rand::{thread_rng, Rng, distributions::Alphanumeric};
 use reqwest::StatusCode;
 use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::Instrument;
 use uuid::Uuid;
 
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
-    #[error("0")]
+    #[error("{0}")]
     ValidationError(String),
+    #[error("The subscription request has invalid fields.")]
+    InvalidFields(FieldErrors),
+    /// Already a problem, from the `Json` or `Form` error handler.
+    #[error(transparent)]
+    UnreadableBody(actix_web::Error),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -27,10 +43,95 @@ impl std::fmt::Debug for SubscribeError {
 impl ResponseError for SubscribeError {
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
-            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::ValidationError(_) | SubscribeError::InvalidFields(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            SubscribeError::UnreadableBody(e) => e.as_response_error().status_code(),
             SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SubscribeError::ValidationError(message) => {
+                Problem::new(ProblemType::InvalidRequest, self.status_code())
+                    .detail(message)
+                    .response()
+            }
+            // Form clients get a problem document as well, rather than a
+            // form-encoded reply: a flat form body could carry neither the
+            // `errors` member nor the request id, and every error response
+            // of the API can then be handled the same way.
+            SubscribeError::InvalidFields(errors) => {
+                Problem::new(ProblemType::InvalidRequest, self.status_code())
+                    .detail(self.to_string())
+                    .extension("errors", errors)
+                    .response()
+            }
+            SubscribeError::UnreadableBody(e) => e.error_response(),
+            SubscribeError::UnexpectedError(_) => Problem::internal_error().response(),
+        }
+    }
+}
+
+/// `Either` reports why the JSON extractor failed even when the body is a
+/// form: the form extractor's error is the relevant one unless the body was
+/// not JSON either.
+fn body_error(error: EitherExtractError<actix_web::Error, actix_web::Error>) -> SubscribeError {
+    let error = match error {
+        EitherExtractError::Extract(json, form)
+            if json.as_response_error().status_code() == StatusCode::UNSUPPORTED_MEDIA_TYPE =>
+        {
+            form
+        }
+        error => error.into(),
+    };
+    SubscribeError::UnreadableBody(error)
+}
+
+/// The format a subscription was posted in, which is also the one we reply in.
+/// Errors are always `application/problem+json`.
+#[derive(Debug, Clone, Copy)]
+pub enum BodyFormat {
+    Json,
+    Form,
+}
+
+impl BodyFormat {
+    fn reply(self, status: StatusCode, body: &impl serde::Serialize) -> HttpResponse {
+        match self {
+            BodyFormat::Json => HttpResponse::build(status).json(body),
+            BodyFormat::Form => match serde_urlencoded::to_string(body) {
+                Ok(body) => HttpResponse::build(status)
+                    .content_type("application/x-www-form-urlencoded")
+                    .body(body),
+                Err(e) => {
+                    tracing::error!("Failed to encode a form response: {:?}", e);
+                    Problem::internal_error().response()
+                }
+            },
+        }
+    }
+}
+
+/// Why each rejected field of a subscription is invalid, keyed by field name.
+pub type FieldErrors = BTreeMap<&'static str, String>;
+
+#[derive(serde::Serialize)]
+struct SubscribeReply {
+    message: &'static str,
+}
+
+impl SubscribeReply {
+    // Identical for new, pending and existing subscribers, so that it does
+    // not reveal who is on the list.
+    fn new(opt_in: OptIn) -> Self {
+        let message = match opt_in {
+            OptIn::Double => "Thanks! Please check your inbox to confirm your subscription.",
+            OptIn::Single => "Thanks for subscribing!",
+        };
+        Self { message }
+    }
 }
 
 impl From<String> for SubscribeError {
@@ -39,57 +140,248 @@ impl From<String> for SubscribeError {
     }
 }
 
+// Missing fields and values of the wrong type deserialize as well, so that
+// they are reported next to the other validation failures instead of as a
+// generic parse error.
 #[derive(serde::Deserialize)]
 pub struct SubscribeData {
-    email: String,
-    name: String,
+    #[serde(default)]
+    email: TextField,
+    #[serde(default)]
+    name: TextField,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(untagged)]
+enum TextField {
+    #[default]
+    Missing,
+    Text(String),
+    /// A number, an object, ...
+    Other(serde::de::IgnoredAny),
+}
+
+impl TextField {
+    fn as_str(&self) -> &str {
+        match self {
+            TextField::Text(text) => text,
+            TextField::Missing | TextField::Other(_) => "",
+        }
+    }
 }
 
 impl TryFrom<SubscribeData> for NewSubscriber {
-    type Error = String;
+    type Error = FieldErrors;
     fn try_from(value: SubscribeData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+        let mut errors = FieldErrors::new();
+        let email = match value.email {
+            TextField::Other(_) => Err("The email address must be a string.".to_string()),
+            TextField::Text(email) if !email.is_empty() => SubscriberEmail::parse(email),
+            _ => Err("An email address is required.".to_string()),
+        };
+        let email = match email {
+            Ok(email) => Some(email),
+            Err(e) => {
+                errors.insert("email", e);
+                None
+            }
+        };
+        let name = match value.name {
+            TextField::Other(_) => Err("The name must be a string.".to_string()),
+            TextField::Text(name) => SubscriberName::parse(name),
+            TextField::Missing => SubscriberName::parse(String::new()),
+        };
+        let name = match name {
+            Ok(name) => Some(name),
+            Err(e) => {
+                errors.insert("name", e);
+                None
+            }
+        };
+        match (email, name) {
+            (Some(email), Some(name)) => Ok(Self { email, name }),
+            _ => Err(errors),
+        }
     }
 }
 
+/// Takes either a JSON or a form-encoded body and replies in the same format,
+/// except for errors, which are `application/problem+json` either way.
+/// With double opt-in (the default) subscribers get a confirmation link, with
+/// single opt-in new ones are confirmed right away and get a welcome email.
+/// Returning subscribers always have to confirm again.
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, base_url),
+    skip(body, pool, email_client, settings),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_email = tracing::field::Empty,
+        subscriber_name = tracing::field::Empty
     )
 )]
 pub async fn subscribe(
-    form: web::Form<SubscribeData>,
+    body: Result<
+        Either<web::Json<SubscribeData>, web::Form<SubscribeData>>,
+        EitherExtractError<actix_web::Error, actix_web::Error>,
+    >,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
-    base_url: web::Data<ApplicationUrl>,
+    email_client: web::Data<dyn EmailSender>,
+    settings: web::Data<SubscriptionSettings>,
 ) -> Result<HttpResponse, SubscribeError>{
+    let body = body.map_err(body_error)?;
+    let opt_in = settings.opt_in;
+    let initial_status = match opt_in {
+        OptIn::Double => SubscriptionStatus::PendingConfirmation,
+        OptIn::Single => SubscriptionStatus::Confirmed,
+    };
     let mut transaction = pool
     .begin()
     .await
     .context("Failed to acquire a Postgre connection from the pool.")?;
 
 
-    let subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
-    let subscriber_id= insert_subscriber(&subscriber, &mut transaction)
+    let (format, data) = match body {
+        Either::Left(json) => (BodyFormat::Json, json.into_inner()),
+        Either::Right(form) => (BodyFormat::Form, form.into_inner()),
+    };
+    let span = tracing::Span::current();
+    span.record("subscriber_email", tracing::field::display(data.email.as_str()));
+    span.record("subscriber_name", tracing::field::display(data.name.as_str()));
+    let subscriber: NewSubscriber = data
+        .try_into()
+        .map_err(SubscribeError::InvalidFields)?;
+    let inserted = insert_subscriber(&subscriber, initial_status, &mut transaction)
     .await
     .context("Failed to insert new subscriber in the database")?;
-    let subscription_token = generate_subscription_token();
-    store_token(&mut transaction, subscriber_id, &subscription_token)
-    .await
-    .context("Failed to store the confirmation token for a new subscriber")?;
+    let (subscriber_id, status) = match inserted {
+        Some(subscriber_id) => (subscriber_id, initial_status),
+        // The address is already on the list. The insert waited for any
+        // concurrent one to commit, so the row is there for us to lock.
+        None => {
+            let existing = find_subscriber_by_email(&mut transaction, &subscriber.email)
+            .await
+            .context("Failed to look up the subscriber by email")?
+            .context("The subscriber disappeared while subscribing again")?;
+            match existing {
+                // The response must not reveal whether the address is on the
+                // list, so confirmed subscribers get the same 200 without an
+                // email. We also stay quiet towards anyone who reported us as
+                // spam.
+                (_, SubscriptionStatus::Confirmed | SubscriptionStatus::Complained) => {
+                    return Ok(format.reply(StatusCode::OK, &SubscribeReply::new(opt_in)));
+                }
+                // Pending subscribers get a fresh link, unless they just got
+                // one, while those who left have to opt in again before they
+                // receive any newsletter, even with single opt-in: their
+                // address may have changed hands in the meantime.
+                (subscriber_id, status) => {
+                    if status == SubscriptionStatus::PendingConfirmation
+                        && confirmation_recently_sent(
+                            &mut transaction,
+                            subscriber_id,
+                            settings.confirmation_resend_cooldown,
+                        )
+                        .await
+                        .context("Failed to read when the last confirmation token was issued")?
+                    {
+                        tracing::info!("Skipping the confirmation email, the address is cooling down");
+                        return Ok(format.reply(StatusCode::OK, &SubscribeReply::new(opt_in)));
+                    }
+                    let next = status
+                    .transition_to(SubscriptionStatus::PendingConfirmation)
+                    .context("Failed to let the subscriber opt in again")?;
+                    update_status(&mut transaction, subscriber_id, next)
+                    .await
+                    .context("Failed to let the subscriber opt in again")?;
+                    (subscriber_id, next)
+                }
+            }
+        }
+    };
+    let subscription_token = match status {
+        SubscriptionStatus::PendingConfirmation => Some(
+            issue_confirmation_token(
+                &mut transaction,
+                subscriber_id,
+                &settings.token_hasher,
+                settings.confirmation_token_ttl,
+            )
+            .await?,
+        ),
+        // Only new subscribers under single opt-in are confirmed already.
+        _ => None,
+    };
+    // Commit before sending, so that the provider round-trip holds neither a
+    // connection nor the lock on the address, and a link that went out always
+    // has its token stored.
     transaction
     .commit()
     .await
     .context("Failed to commit transaction to store a subscriber.")?;
-    send_confirmation_email(&email_client, subscriber, &base_url.0, &subscription_token)
-    .await
-    .context("Failed to send a confirmation email")?;
-    Ok(HttpResponse::Ok().finish())
+    // Reply before sending, as for the addresses answered above: neither the
+    // outcome nor the time the email provider takes may tell the caller
+    // whether the address was already on the list.
+    tokio::spawn(
+        async move {
+            let (sent, context) = match &subscription_token {
+                Some(subscription_token) => (
+                    send_confirmation_email(
+                        email_client.get_ref(),
+                        &subscriber.email,
+                        &settings.base_url,
+                        subscription_token,
+                    )
+                    .await,
+                    "Failed to send a confirmation email",
+                ),
+                None => {
+                    let unsubscribe_url = settings.unsubscribe_links.url(subscriber_id);
+                    (
+                        send_welcome_email(email_client.get_ref(), &subscriber.email, &unsubscribe_url)
+                            .await,
+                        "Failed to send a welcome email",
+                    )
+                }
+            };
+            let outcome = match sent {
+                Ok(()) => Ok(()),
+                Err(EmailClientError::InactiveRecipient { .. }) => {
+                    tracing::warn!("The email provider refused the address, marking it as bounced");
+                    mark_as_bounced(&pool, subscriber_id).await
+                }
+                Err(e) => Err(anyhow::Error::new(e).context(context)),
+            };
+            if let Err(e) = outcome {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to follow up on a subscription"
+                );
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+    Ok(format.reply(StatusCode::OK, &SubscribeReply::new(opt_in)))
+}
+
+/// The provider refused to write to the address, so neither do we: the
+/// subscriber is set aside and any confirmation link it was sent stops
+/// working.
+async fn mark_as_bounced(pool: &PgPool, subscriber_id: Uuid) -> Result<(), anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    transition_subscriber(&mut transaction, subscriber_id, SubscriptionStatus::Bounced)
+        .await
+        .context("Failed to mark the subscriber as bounced")?;
+    delete_tokens(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to remove the confirmation tokens of a bounced subscriber")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the bounce")?;
+    Ok(())
 }
 
 // #[derive(Debug)]
@@ -111,7 +403,11 @@ trying to store a subscription token."
     }
 }
 
-impl actix_web::ResponseError for StoreTokenError {}
+impl actix_web::ResponseError for StoreTokenError {
+    fn error_response(&self) -> HttpResponse {
+        Problem::internal_error().response()
+    }
+}
 
 impl std::error::Error for StoreTokenError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
@@ -119,20 +415,46 @@ impl std::error::Error for StoreTokenError {
     }
 }
 
+/// Replaces any earlier confirmation token of the subscriber with a fresh one
+/// and returns it. Only its hash ends up in the database.
+pub async fn issue_confirmation_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    token_hasher: &SubscriptionTokenHasher,
+    ttl: chrono::Duration,
+) -> Result<String, anyhow::Error> {
+    delete_tokens(transaction, subscriber_id)
+        .await
+        .context("Failed to remove the previous confirmation tokens")?;
+    let subscription_token = generate_subscription_token();
+    store_token(
+        transaction,
+        subscriber_id,
+        &token_hasher.hash(&subscription_token),
+        Utc::now() + ttl,
+    )
+    .await
+    .context("Failed to store the confirmation token")?;
+    Ok(subscription_token)
+}
+
+/// Expects the hash of the token, never the token itself.
 #[tracing::instrument(
     name = "Storing token",
-    skip(transaction, subscription_token)
+    skip(transaction, token_hash)
 )]
 pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
-    subscription_token: &str,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
 ) -> Result<(), StoreTokenError>{
     sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-        VALUES ($1, $2)"#,
-        subscription_token,
-        subscriber_id
+        r#"INSERT INTO subscription_tokens (token_hash, subscriber_id, created_at, expires_at)
+        VALUES ($1, $2, now(), $3)"#,
+        token_hash,
+        subscriber_id,
+        expires_at
         )
         .execute(transaction)
         .await
@@ -145,58 +467,181 @@ pub async fn store_token(
 
 #[tracing::instrument(
     name = "Sending confirmation email",
-    skip(email_client, subscriber, base_url, subscription_token)
+    skip(email_client, recipient, base_url, subscription_token)
 )]
-async fn send_confirmation_email(
-    email_client: &EmailClient,
-    subscriber: NewSubscriber,
+pub async fn send_confirmation_email(
+    email_client: &dyn EmailSender,
+    recipient: &SubscriberEmail,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), EmailClientError> {
     let confirmation_link = format!("{}/subscriptions/confirm?subscription_token={}", base_url, subscription_token);
-    let plain_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
     let html_body = format!(
         "Welcome to our newsletter!<br />\
-        lick <a href=\"{}\">here</a> to confirm your subscription.",
+        Click <a href=\"{}\">here</a> to confirm your subscription.",
         confirmation_link
     );
+    let plain_body = plain_text::from_html(&html_body)
+        .map_err(|e| EmailClientError::UnexpectedError(Arc::new(e)))?;
 
     email_client
-        .send_email(&subscriber.email, "Welcome!", &html_body, &plain_body)
+        .send_email(recipient, "Welcome!", &html_body, &plain_body)
         .await
 }
 
+#[tracing::instrument(
+    name = "Sending welcome email",
+    skip(email_client, recipient, unsubscribe_url)
+)]
+async fn send_welcome_email(
+    email_client: &dyn EmailSender,
+    recipient: &SubscriberEmail,
+    unsubscribe_url: &str,
+) -> Result<(), EmailClientError> {
+    let html_body = format!(
+        "Welcome to our newsletter! You will get our next issue in your inbox.<br />\
+        Changed your mind? <a href=\"{}\">Unsubscribe</a>.",
+        unsubscribe_url
+    );
+    let plain_body = plain_text::from_html(&html_body)
+        .map_err(|e| EmailClientError::UnexpectedError(Arc::new(e)))?;
 
+    email_client
+        .send(&OutgoingEmail {
+            recipient,
+            subject: "Welcome!",
+            html_content: &html_body,
+            text_content: &plain_body,
+            unsubscribe_url: Some(unsubscribe_url),
+        })
+        .await
+}
+
+/// Returns `None`, without touching the existing row, if the email address
+/// is already on the list.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
     skip(new_subscriber, transaction)
 )]
 pub async fn insert_subscriber(
     new_subscriber: &NewSubscriber,
+    status: SubscriptionStatus,
     transaction: &mut Transaction<'_, Postgres>,
-) -> Result<Uuid, sqlx::Error> {
-    let id = Uuid::new_v4();
-    sqlx::query!(
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
         r#"
         INSERT INTO subscriptions(id, email, name, subscribed_at, status)
         VALUES($1, $2, $3, $4, $5)
+        ON CONFLICT (email) DO NOTHING
+        RETURNING id
         "#,
-        id,
+        Uuid::new_v4(),
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
         Utc::now(),
-        "pending_confirmation",
+        status as SubscriptionStatus,
     )
-    .execute(transaction)
+    .fetch_optional(transaction)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
     })?;
-    Ok(id)
+    Ok(row.map(|r| r.id))
+}
+
+#[tracing::instrument(name = "Looking up a subscriber by email", skip(transaction, email))]
+pub async fn find_subscriber_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<(Uuid, SubscriptionStatus)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT id, status AS "status: SubscriptionStatus"
+        FROM subscriptions
+        WHERE email = $1
+        FOR UPDATE
+        "#,
+        email.as_ref()
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|r| (r.id, r.status)))
+}
+
+#[tracing::instrument(name = "Removing confirmation tokens", skip(transaction))]
+async fn delete_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[derive(thiserror::Error)]
+pub enum TransitionError {
+    #[error("There is no subscriber with the given id.")]
+    UnknownSubscriber,
+    #[error(transparent)]
+    InvalidTransition(#[from] InvalidTransition),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for TransitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Moves a subscriber to `next`, refusing transitions the lifecycle does not
+/// allow. The row is locked so concurrent requests see each other's changes;
+/// the caller commits the transaction.
+#[tracing::instrument(name = "Change the status of a subscriber", skip(transaction))]
+pub async fn transition_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    next: SubscriptionStatus,
+) -> Result<(), TransitionError> {
+    let current = sqlx::query!(
+        r#"
+        SELECT status AS "status: SubscriptionStatus"
+        FROM subscriptions
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to read the status of the subscriber")?
+    .ok_or(TransitionError::UnknownSubscriber)?
+    .status;
+    let next = current.transition_to(next)?;
+    update_status(transaction, subscriber_id, next)
+        .await
+        .context("Failed to update the status of the subscriber")?;
+    Ok(())
+}
+
+async fn update_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    status: SubscriptionStatus,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = $1 WHERE id = $2"#,
+        status as SubscriptionStatus,
+        subscriber_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
 }
 
 fn generate_subscription_token() -> String {
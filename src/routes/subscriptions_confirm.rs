@@ -1,5 +1,12 @@
-use actix_web::{HttpResponse, web};
-use sqlx::PgPool;
+use crate::domain::SubscriptionStatus;
+use crate::problem::{Problem, ProblemType};
+use crate::routes::{error_chain_fmt, transition_subscriber, TransitionError};
+use crate::startup::SubscriptionSettings;
+use actix_web::{HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
@@ -7,50 +14,132 @@ pub struct Parameters  {
     subscription_token: String
 }
 
-#[tracing::instrument( name = "Confirm a pending subscriber",
-    skip(parameters, pool)
-)]
-pub async fn confirm(parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let id = match get_subscriber_id_from_token(&pool, &parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match id {
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-    HttpResponse::Ok().finish()
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error("The confirmation link is not valid.")]
+    UnknownToken,
+    #[error("The confirmation link has expired. Please subscribe again to get a new one.")]
+    ExpiredToken,
+    #[error("The confirmation link has already been used.")]
+    UsedToken,
+    // Someone who unsubscribed (or whose address stopped working) must opt in
+    // again; an old link is not enough.
+    #[error("The subscription can no longer be confirmed with this link.")]
+    InvalidTransition,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::ExpiredToken => StatusCode::GONE,
+            ConfirmError::UsedToken | ConfirmError::InvalidTransition => StatusCode::CONFLICT,
+            ConfirmError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem_type = match self {
+            ConfirmError::UnknownToken => ProblemType::InvalidConfirmationLink,
+            ConfirmError::ExpiredToken => ProblemType::ExpiredConfirmationLink,
+            ConfirmError::UsedToken => ProblemType::UsedConfirmationLink,
+            ConfirmError::InvalidTransition => ProblemType::ConfirmationNotAllowed,
+            ConfirmError::UnexpectedError(_) => return Problem::internal_error().response(),
+        };
+        Problem::new(problem_type, self.status_code())
+            .detail(self.to_string())
+            .response()
+    }
+}
+
+impl From<TransitionError> for ConfirmError {
+    fn from(e: TransitionError) -> Self {
+        match e {
+            TransitionError::InvalidTransition(_) => ConfirmError::InvalidTransition,
+            // The token outlived its subscriber.
+            TransitionError::UnknownSubscriber => ConfirmError::UnknownToken,
+            TransitionError::UnexpectedError(e) => ConfirmError::UnexpectedError(e),
         }
     }
 }
 
-pub async fn get_subscriber_id_from_token(pool: &PgPool, subscription_token: &str) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#, 
-        subscription_token)
-        .fetch_optional(pool)
+struct StoredToken {
+    subscriber_id: Uuid,
+    expires_at: DateTime<Utc>,
+    consumed_at: Option<DateTime<Utc>>,
+}
+
+#[tracing::instrument( name = "Confirm a pending subscriber",
+    skip(parameters, pool, settings)
+)]
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, ConfirmError> {
+    let token_hash = settings.token_hasher.hash(&parameters.subscription_token);
+    let mut transaction = pool
+        .begin()
         .await
-        .map_err(|e| {
-            tracing::error!(
-                "Failed to execute query:{:?}",
-                e
-            );
-            e
-        })?;
-        Ok(result.map(|r| r.subscriber_id))
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let token = get_token(&mut transaction, &token_hash)
+        .await
+        .context("Failed to retrieve the confirmation token")?
+        .ok_or(ConfirmError::UnknownToken)?;
+    if token.consumed_at.is_some() {
+        return Err(ConfirmError::UsedToken);
+    }
+    if token.expires_at <= Utc::now() {
+        return Err(ConfirmError::ExpiredToken);
+    }
+    transition_subscriber(&mut transaction, token.subscriber_id, SubscriptionStatus::Confirmed)
+        .await?;
+    consume_token(&mut transaction, &token_hash)
+        .await
+        .context("Failed to mark the confirmation token as used")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the confirmation")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Locks the token so that two concurrent clicks cannot both use it.
+async fn get_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    token_hash: &str,
+) -> Result<Option<StoredToken>, sqlx::Error> {
+    sqlx::query_as!(
+        StoredToken,
+        r#"
+        SELECT subscriber_id, expires_at, consumed_at
+        FROM subscription_tokens
+        WHERE token_hash = $1
+        FOR UPDATE
+        "#,
+        token_hash
+    )
+    .fetch_optional(transaction)
+    .await
 }
 
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+async fn consume_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    token_hash: &str,
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#, 
-        subscriber_id)
-        .execute(pool)
-        .await
-        .map_err(|e|{
-            tracing::error!("Failed to execute query:{:?}", e);
-            e
-        })?;
-        Ok(())
-}
\ No newline at end of file
+        r#"UPDATE subscription_tokens SET consumed_at = now() WHERE token_hash = $1"#,
+        token_hash
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use crate::domain::{SubscriberEmail, SubscriptionStatus};
+use crate::email_client::EmailSender;
+use crate::routes::{
+    find_subscriber_by_email, issue_confirmation_token, send_confirmation_email, SubscribeError,
+};
+use crate::startup::SubscriptionSettings;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Instrument;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct ResendConfirmationData {
+    email: String,
+}
+
+/// Sends a new confirmation link to a pending subscriber. Every valid address
+/// gets the same response, whether or not it is on the list.
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip_all,
+    fields(subscriber_email = %form.email)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<ResendConfirmationData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, SubscribeError> {
+    let email = SubscriberEmail::parse(form.0.email).map_err(SubscribeError::ValidationError)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber_id = match find_subscriber_by_email(&mut transaction, &email)
+        .await
+        .context("Failed to look up the subscriber by email")?
+    {
+        Some((subscriber_id, SubscriptionStatus::PendingConfirmation)) => subscriber_id,
+        _ => return Ok(HttpResponse::Ok().finish()),
+    };
+    if confirmation_recently_sent(
+        &mut transaction,
+        subscriber_id,
+        settings.confirmation_resend_cooldown,
+    )
+    .await
+    .context("Failed to read when the last confirmation token was issued")?
+    {
+        tracing::info!("Skipping the confirmation email, the address is cooling down");
+        return Ok(HttpResponse::Ok().finish());
+    }
+    let subscription_token = issue_confirmation_token(
+        &mut transaction,
+        subscriber_id,
+        &settings.token_hasher,
+        settings.confirmation_token_ttl,
+    )
+    .await?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the new confirmation token")?;
+    // We reply before sending, so that neither the outcome nor the time the
+    // email provider takes tells the caller that the address is pending.
+    let base_url = settings.base_url.clone();
+    tokio::spawn(
+        async move {
+            if let Err(e) = send_confirmation_email(
+                email_client.get_ref(),
+                &email,
+                &base_url,
+                &subscription_token,
+            )
+            .await
+            {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to resend a confirmation email"
+                );
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Whether the subscriber was sent a confirmation link less than `cooldown`
+/// ago.
+pub async fn confirmation_recently_sent(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    cooldown: chrono::Duration,
+) -> Result<bool, sqlx::Error> {
+    let last_sent_at = last_token_issued_at(transaction, subscriber_id).await?;
+    Ok(matches!(last_sent_at, Some(last_sent_at) if Utc::now() < last_sent_at + cooldown))
+}
+
+async fn last_token_issued_at(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Option<chrono::DateTime<Utc>>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT max(created_at) AS "created_at" FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .fetch_one(transaction)
+    .await?;
+    Ok(row.created_at)
+}
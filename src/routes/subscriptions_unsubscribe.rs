@@ -0,0 +1,130 @@
+use crate::domain::SubscriptionStatus;
+use crate::problem::{Problem, ProblemType};
+use crate::routes::{error_chain_fmt, transition_subscriber, TransitionError};
+use crate::startup::SubscriptionSettings;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe link is not valid.")]
+    InvalidLink,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::InvalidLink => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            UnsubscribeError::InvalidLink => {
+                Problem::new(ProblemType::InvalidUnsubscribeLink, self.status_code())
+                    .detail(self.to_string())
+                    .response()
+            }
+            UnsubscribeError::UnexpectedError(_) => Problem::internal_error().response(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    subscriber_id: Uuid,
+    signature: String,
+}
+
+/// Serves the link in the email body. Mail scanners follow links, so opening it
+/// only asks for confirmation: the form posts back to `unsubscribe`.
+#[tracing::instrument(
+    name = "Show the unsubscribe confirmation page",
+    skip(parameters, settings),
+    fields(subscriber_id = %parameters.subscriber_id)
+)]
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !settings.unsubscribe_links.verify(parameters.subscriber_id, &parameters.signature) {
+        return Err(UnsubscribeError::InvalidLink);
+    }
+    // The signature checked out, so it is plain hex and safe to embed as is.
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta http-equiv="content-type" content="text/html; charset=utf-8"><title>Unsubscribe</title></head>
+<body>
+<h1>Unsubscribe</h1>
+<p>Do you want to stop receiving our newsletter?</p>
+<form action="/subscriptions/unsubscribe?subscriber_id={}&amp;signature={}" method="post">
+<input type="hidden" name="List-Unsubscribe" value="One-Click">
+<button type="submit">Unsubscribe</button>
+</form>
+</body>
+</html>"#,
+            parameters.subscriber_id, parameters.signature
+        )))
+}
+
+/// Handles one-click unsubscribe requests from mail clients (RFC 8058) and
+/// the form of `unsubscribe_form`, which carry the same query.
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(parameters, pool, settings),
+    fields(subscriber_id = %parameters.subscriber_id)
+)]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !settings.unsubscribe_links.verify(parameters.subscriber_id, &parameters.signature) {
+        return Err(UnsubscribeError::InvalidLink);
+    }
+    match unsubscribe_subscriber(&pool, parameters.subscriber_id).await {
+        // Bounced and complained subscribers already get no emails, and a
+        // deleted one has nothing left to unsubscribe from.
+        Ok(())
+        | Err(TransitionError::InvalidTransition(_))
+        | Err(TransitionError::UnknownSubscriber) => {
+            Ok(HttpResponse::Ok().body("You have been unsubscribed."))
+        }
+        Err(TransitionError::UnexpectedError(e)) => Err(UnsubscribeError::UnexpectedError(
+            e.context("Failed to unsubscribe the subscriber"),
+        )),
+    }
+}
+
+pub async fn unsubscribe_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<(), TransitionError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    transition_subscriber(&mut transaction, subscriber_id, SubscriptionStatus::Unsubscribed)
+        .await?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the status change")?;
+    Ok(())
+}
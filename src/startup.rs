@@ -1,36 +1,64 @@
-use crate::configuration::{Settings, DatabaseSettings};
-use crate::email_client::EmailClient;
-use crate::routes::{health_check, subscribe, confirm, publish_newsletter};
+use crate::configuration::{
+    DatabaseSettings, EmailProvider, IssueDeliverySettings, OptIn, Settings,
+};
+use crate::email_client::EmailSender;
+use crate::issue_delivery_worker::worker_loop;
+use crate::newsletter_scheduler::scheduler_loop;
+use crate::problem::{add_request_id, form_error, json_error, path_error, query_error, Problem};
+use crate::routes::{
+    cancel_scheduled_issue, confirm, get_delivery_summary, get_issue, health_check, list_issues,
+    list_mailbox_messages, list_scheduled_issues, publish_newsletter, reschedule_issue,
+    resend_confirmation, send_newsletter_preview, show_mailbox_message, subscribe, unsubscribe,
+    unsubscribe_form,
+};
+use crate::subscription_tokens::{hash_outstanding_tokens, SubscriptionTokenHasher};
+use crate::unsubscribe::UnsubscribeLinks;
 use actix_web::dev::Server;
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpRequest, HttpServer, Responder};
 use secrecy::ExposeSecret;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 
 pub struct Application {
     port: u16,
     server: Server,
+    connection_pool: PgPool,
+    worker_email_client: Arc<dyn EmailSender>,
+    issue_delivery_settings: IssueDeliverySettings,
+    email_timeout: std::time::Duration,
+    unsubscribe_links: UnsubscribeLinks,
+    token_hasher: SubscriptionTokenHasher,
 }
 
 impl Application {
     pub fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = PgPoolOptions::new()
             .connect_timeout(std::time::Duration::from_secs(2))
-            .connect_lazy(&configuration.database.connection_string().expose_secret())
+            .connect_lazy(configuration.database.connection_string().expose_secret())
             .expect("Failed to connect to Postgres");
 
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address");
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url.clone(),
-            sender_email,
-            configuration.email_client.authorization_token.clone(),
-            configuration.email_client.timeout(),
-        );
+        let email_client = configuration.email_client.client(&connection_pool);
+        let mailbox_enabled = matches!(configuration.email_client.provider, EmailProvider::Mailbox);
+        let worker_email_client = email_client.clone();
+        let subscription_settings = SubscriptionSettings {
+            confirmation_token_ttl: configuration.application.confirmation_token_ttl(),
+            confirmation_resend_cooldown: configuration.application.confirmation_resend_cooldown(),
+            opt_in: configuration.application.opt_in,
+            unsubscribe_links: UnsubscribeLinks::new(
+                configuration.application.base_url.clone(),
+                configuration.application.hmac_secret.clone(),
+            ),
+            token_hasher: SubscriptionTokenHasher::new(
+                configuration.application.hmac_secret.clone(),
+            ),
+            base_url: configuration.application.base_url,
+        };
+        let unsubscribe_links = subscription_settings.unsubscribe_links.clone();
+        let token_hasher = subscription_settings.token_hasher.clone();
 
         let address = format!(
             "{}:{}",
@@ -38,42 +66,128 @@ impl Application {
         );
         let lst = TcpListener::bind(address).expect("Failed to bind port");
         let port = lst.local_addr().unwrap().port();
-        let server = run(lst, connection_pool, email_client, configuration.application.base_url)?;
-        Ok(Self{port, server})
+        let server = run(
+            lst,
+            connection_pool.clone(),
+            email_client,
+            subscription_settings,
+            mailbox_enabled,
+        )?;
+        Ok(Self {
+            port,
+            server,
+            connection_pool,
+            worker_email_client,
+            issue_delivery_settings: configuration.issue_delivery,
+            email_timeout: configuration.email_client.timeout(),
+            unsubscribe_links,
+            token_hasher,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
-    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+    /// Runs the HTTP server next to the issue delivery worker and the
+    /// newsletter scheduler, returning as soon as any of them stops.
+    pub async fn run_until_stopped(self) -> Result<(), anyhow::Error> {
+        // Links that are still plain text stop working until this succeeds on
+        // a later start, which is no reason to keep the server down.
+        if let Err(e) = hash_outstanding_tokens(&self.connection_pool, &self.token_hasher).await {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to hash the outstanding subscription tokens"
+            );
+        }
+        let scheduler = scheduler_loop(self.connection_pool.clone());
+        let worker = worker_loop(
+            self.connection_pool,
+            self.worker_email_client,
+            self.issue_delivery_settings,
+            self.unsubscribe_links,
+            self.email_timeout,
+        );
+        tokio::select! {
+            outcome = self.server => outcome.map_err(anyhow::Error::from),
+            outcome = worker => outcome,
+            outcome = scheduler => outcome,
+        }
     }
 }
 
-pub struct ApplicationUrl(pub String);
+/// What the subscription endpoints need besides the database and the email
+/// client.
+pub struct SubscriptionSettings {
+    /// Where the links we email point to.
+    pub base_url: String,
+    pub confirmation_token_ttl: chrono::Duration,
+    pub confirmation_resend_cooldown: chrono::Duration,
+    pub opt_in: OptIn,
+    pub unsubscribe_links: UnsubscribeLinks,
+    pub token_hasher: SubscriptionTokenHasher,
+}
 
 pub fn run(
     lst: TcpListener,
     connection: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    email_client: Arc<dyn EmailSender>,
+    subscription_settings: SubscriptionSettings,
+    mailbox_enabled: bool,
 ) -> Result<Server, std::io::Error> {
     let connection = web::Data::new(connection);
-    let email_client = web::Data::new(email_client);
-    let base_url = web::Data::new(ApplicationUrl(base_url));
+    let email_client: web::Data<dyn EmailSender> = web::Data::from(email_client);
+    let subscription_settings = web::Data::new(subscription_settings);
 
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(from_fn(add_request_id))
             .wrap(TracingLogger::default())
             .route("/", web::get().to(greet))
             .route("/subscriptions", web::post().to(subscribe))
             .route("/subscriptions/confirm", web::get().to(confirm))
+            .route(
+                "/subscriptions/resend-confirmation",
+                web::post().to(resend_confirmation),
+            )
+            .service(
+                web::resource("/subscriptions/unsubscribe")
+                    .route(web::get().to(unsubscribe_form))
+                    .route(web::post().to(unsubscribe)),
+            )
             .route("/health_check", web::get().to(health_check))
             .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/newsletters/preview", web::post().to(send_newsletter_preview))
+            .route("/newsletters/issues", web::get().to(list_issues))
+            .route(
+                "/newsletters/issues/{newsletter_issue_id}",
+                web::get().to(get_issue),
+            )
+            .route("/newsletters/scheduled", web::get().to(list_scheduled_issues))
+            .service(
+                web::resource("/newsletters/scheduled/{newsletter_issue_id}")
+                    .route(web::put().to(reschedule_issue))
+                    .route(web::delete().to(cancel_scheduled_issue)),
+            )
+            .route(
+                "/newsletters/{newsletter_issue_id}/delivery",
+                web::get().to(get_delivery_summary),
+            )
+            .configure(|cfg| {
+                if mailbox_enabled {
+                    cfg.route("/dev/mailbox", web::get().to(list_mailbox_messages))
+                        .route("/dev/mailbox/{message_id}", web::get().to(show_mailbox_message));
+                }
+            })
+            .default_service(web::to(|| async { Problem::not_found().response() }))
+            .app_data(web::JsonConfig::default().error_handler(json_error))
+            .app_data(web::FormConfig::default().error_handler(form_error))
+            .app_data(web::QueryConfig::default().error_handler(query_error))
+            .app_data(web::PathConfig::default().error_handler(path_error))
             .app_data(connection.clone())
             .app_data(email_client.clone())
-            .app_data(base_url.clone())
+            .app_data(subscription_settings.clone())
     })
     .listen(lst)?
     .run();
@@ -0,0 +1,93 @@
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::PgPool;
+
+/// Turns confirmation tokens into the keyed hashes we keep in
+/// `subscription_tokens`. Only the subscriber ever sees the token itself, so
+/// a leaked database or backup cannot be used to confirm pending addresses.
+#[derive(Clone)]
+pub struct SubscriptionTokenHasher {
+    hmac_secret: Secret<String>,
+}
+
+impl SubscriptionTokenHasher {
+    pub fn new(hmac_secret: Secret<String>) -> Self {
+        Self { hmac_secret }
+    }
+
+    pub fn hash(&self, subscription_token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.hmac_secret.expose_secret().as_bytes())
+            .expect("HMAC can take keys of any size");
+        mac.update(b"subscription_token:");
+        mac.update(subscription_token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Hashes the tokens that were handed out before we stopped storing them in
+/// plain text, so that their links keep working until they expire.
+#[tracing::instrument(name = "Hash outstanding subscription tokens", skip_all)]
+pub async fn hash_outstanding_tokens(
+    pool: &PgPool,
+    hasher: &SubscriptionTokenHasher,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let outstanding = sqlx::query!(
+        r#"SELECT token_hash FROM subscription_tokens WHERE NOT is_hashed FOR UPDATE"#
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch the plain-text subscription tokens")?;
+    for row in outstanding {
+        sqlx::query!(
+            r#"
+            UPDATE subscription_tokens
+            SET token_hash = $1, is_hashed = true
+            WHERE token_hash = $2
+            "#,
+            hasher.hash(&row.token_hash),
+            row.token_hash
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to hash a plain-text subscription token")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the hashed subscription tokens")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionTokenHasher;
+    use secrecy::Secret;
+
+    fn hasher(secret: &str) -> SubscriptionTokenHasher {
+        SubscriptionTokenHasher::new(Secret::new(secret.into()))
+    }
+
+    #[test]
+    fn the_same_token_always_has_the_same_hash() {
+        let hasher = hasher("secret");
+        assert_eq!(hasher.hash("token"), hasher.hash("token"));
+    }
+
+    #[test]
+    fn the_hash_does_not_contain_the_token() {
+        let hash = hasher("secret").hash("a-recognisable-token");
+        assert!(!hash.contains("a-recognisable-token"));
+        assert_eq!(hash.len(), 64);
+    }
+
+    #[test]
+    fn the_hash_depends_on_the_secret() {
+        assert_ne!(hasher("secret").hash("token"), hasher("other").hash("token"));
+    }
+}
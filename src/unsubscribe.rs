@@ -0,0 +1,83 @@
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Builds and checks the per-subscriber unsubscribe links we put in every
+/// newsletter. Links are signed, so nobody can unsubscribe someone else by
+/// guessing their id.
+#[derive(Clone)]
+pub struct UnsubscribeLinks {
+    base_url: String,
+    hmac_secret: Secret<String>,
+}
+
+impl UnsubscribeLinks {
+    pub fn new(base_url: String, hmac_secret: Secret<String>) -> Self {
+        Self {
+            base_url,
+            hmac_secret,
+        }
+    }
+
+    pub fn url(&self, subscriber_id: Uuid) -> String {
+        let signature = hex::encode(self.mac(subscriber_id).finalize().into_bytes());
+        format!(
+            "{}/subscriptions/unsubscribe?subscriber_id={}&signature={}",
+            self.base_url, subscriber_id, signature
+        )
+    }
+
+    /// Checks `signature` in constant time.
+    pub fn verify(&self, subscriber_id: Uuid, signature: &str) -> bool {
+        match hex::decode(signature) {
+            Ok(signature) => self.mac(subscriber_id).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn mac(&self, subscriber_id: Uuid) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.hmac_secret.expose_secret().as_bytes())
+            .expect("HMAC can take keys of any size");
+        mac.update(b"unsubscribe:");
+        mac.update(subscriber_id.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnsubscribeLinks;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn links(secret: &str) -> UnsubscribeLinks {
+        UnsubscribeLinks::new("http://127.0.0.1".into(), Secret::new(secret.into()))
+    }
+
+    fn signature_of(url: &str) -> String {
+        reqwest::Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .find(|(name, _)| name == "signature")
+            .unwrap()
+            .1
+            .into_owned()
+    }
+
+    #[test]
+    fn links_signed_with_our_secret_are_valid() {
+        let subscriber_id = Uuid::new_v4();
+        let url = links("secret").url(subscriber_id);
+        assert!(links("secret").verify(subscriber_id, &signature_of(&url)));
+    }
+
+    #[test]
+    fn signatures_are_tied_to_the_subscriber_and_the_secret() {
+        let subscriber_id = Uuid::new_v4();
+        let signature = signature_of(&links("secret").url(subscriber_id));
+        assert!(!links("secret").verify(Uuid::new_v4(), &signature));
+        assert!(!links("another secret").verify(subscriber_id, &signature));
+        assert!(!links("secret").verify(subscriber_id, "not-hex"));
+    }
+}
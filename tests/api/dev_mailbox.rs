@@ -0,0 +1,54 @@
+use zero2prod::configuration::EmailProvider;
+
+use crate::helpers::{spawn_app, spawn_app_with};
+
+#[tokio::test]
+async fn emails_are_stored_in_the_mailbox_instead_of_being_sent() {
+    let app = spawn_app_with(|c| c.email_client.provider = EmailProvider::Mailbox).await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let response = app.post_subscription(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+    assert!(app.email_server.received_requests().await.unwrap().is_empty());
+
+    // The email is stored after the response, so give it a moment.
+    let mut message = None;
+    for _ in 0..100 {
+        message = sqlx::query!("SELECT message_id, recipient, subject FROM mailbox_messages")
+            .fetch_optional(&app.db_pool)
+            .await
+            .unwrap();
+        if message.is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let message = message.expect("Failed to fetch the stored email");
+    assert_eq!(message.recipient, "ursula_le_guin@gmail.com");
+
+    let list = reqwest::get(&format!("{}/dev/mailbox", app.address))
+        .await
+        .unwrap();
+    assert_eq!(200, list.status().as_u16());
+    let list = list.text().await.unwrap();
+    assert!(list.contains("ursula_le_guin@gmail.com"));
+    assert!(list.contains(&format!("/dev/mailbox/{}", message.message_id)));
+
+    let detail = reqwest::get(&format!("{}/dev/mailbox/{}", app.address, message.message_id))
+        .await
+        .unwrap();
+    assert_eq!(200, detail.status().as_u16());
+    let detail = detail.text().await.unwrap();
+    assert!(detail.contains(&message.subject));
+    assert!(detail.contains("/subscriptions/confirm?subscription_token="));
+}
+
+#[tokio::test]
+async fn the_mailbox_is_not_exposed_for_other_providers() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!("{}/dev/mailbox", app.address))
+        .await
+        .unwrap();
+    assert_eq!(404, response.status().as_u16());
+}
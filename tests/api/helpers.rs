@@ -1,13 +1,21 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
-use secrecy::ExposeSecret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
-use std::net::TcpListener;
+use std::collections::HashMap;
+use std::sync::Arc;
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
-use zero2prod::email_client::EmailClient;
-use zero2prod::startup::{get_connection_pool, run, Application};
+use wiremock::{MockServer, Respond, ResponseTemplate};
+use zero2prod::configuration::{
+    get_configuration, DatabaseSettings, EmailProvider, IssueDeliverySettings, Settings,
+};
+use zero2prod::email_client::EmailSender;
+use zero2prod::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use zero2prod::newsletter_scheduler::release_due_issue;
+use zero2prod::startup::Application;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::subscription_tokens::SubscriptionTokenHasher;
+use zero2prod::unsubscribe::UnsubscribeLinks;
 
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
@@ -21,23 +29,188 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     }
 });
 
+/// Answers Postmark `/email/batch` requests with one result per message,
+/// failing the messages sent to the configured recipients.
+#[derive(Default)]
+pub struct PostmarkBatchResponder {
+    failing_recipients: HashMap<String, i64>,
+}
+
+impl PostmarkBatchResponder {
+    pub fn failing(mut self, recipient: &str, error_code: i64) -> Self {
+        self.failing_recipients
+            .insert(recipient.to_string(), error_code);
+        self
+    }
+}
+
+impl Respond for PostmarkBatchResponder {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                let to = message["To"].as_str().unwrap();
+                match self.failing_recipients.get(to) {
+                    Some(error_code) => serde_json::json!({
+                        "ErrorCode": error_code,
+                        "Message": "The message could not be delivered.",
+                        "To": to,
+                    }),
+                    None => serde_json::json!({"ErrorCode": 0, "Message": "OK", "To": to}),
+                }
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
 pub struct ConfirmationLinks {
     pub html: reqwest::Url,
     pub plain_text: reqwest::Url,
 }
 
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    async fn store(&self, pool: &PgPool) {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        let password_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(15000, 2, 1, None).unwrap(),
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash)
+            VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash,
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
+
 pub struct TestApp {
     pub address: String,
     pub port: u16,
     pub db_pool: PgPool,
     pub email_server: MockServer,
+    pub test_user: TestUser,
+    pub email_client: Arc<dyn EmailSender>,
+    pub issue_delivery_settings: IssueDeliverySettings,
+    pub email_timeout: std::time::Duration,
+    pub unsubscribe_links: UnsubscribeLinks,
+    pub token_hasher: SubscriptionTokenHasher,
 }
 
 impl TestApp {
-    //
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(
+                    &self.db_pool,
+                    self.email_client.as_ref(),
+                    &self.issue_delivery_settings,
+                    &self.unsubscribe_links,
+                    self.email_timeout,
+                )
+                .await
+                .unwrap()
+            {
+                break;
+            }
+        }
+        // The application's own worker may still be delivering a task
+        // it claimed before we got to it.
+        loop {
+            let pending = sqlx::query!(
+                r#"
+                SELECT COUNT(*) AS "count!" FROM issue_delivery_queue
+                WHERE execute_after <= now() OR claimed_until > now()
+                "#
+            )
+                .fetch_one(&self.db_pool)
+                .await
+                .unwrap()
+                .count;
+            if pending == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Makes every scheduled issue due and hands them over to the delivery queue.
+    pub async fn release_scheduled_issues(&self) {
+        sqlx::query!("UPDATE scheduled_newsletter_issues SET send_at = now()")
+            .execute(&self.db_pool)
+            .await
+            .unwrap();
+        while let ExecutionOutcome::TaskCompleted = release_due_issue(&self.db_pool).await.unwrap() {}
+        // Same as above: the application's scheduler may be releasing one.
+        loop {
+            let pending = sqlx::query!(
+                r#"SELECT COUNT(*) AS "count!" FROM scheduled_newsletter_issues"#
+            )
+            .fetch_one(&self.db_pool)
+            .await
+            .unwrap()
+            .count;
+            if pending == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    pub async fn post_subscription_json(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_resend_confirmation(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/resend-confirmation", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Moves the last confirmation email out of the cooldown window.
+    pub async fn backdate_confirmation_tokens(&self) {
+        sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 day'")
+            .execute(&self.db_pool)
+            .await
+            .unwrap();
+    }
+
     pub async fn post_subscription(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -45,6 +218,18 @@ impl TestApp {
             .expect("Failed to execute request")
     }
 
+    /// Subscription emails go out after the response, so give them a moment.
+    pub async fn wait_for_emails(&self, n: usize) -> Vec<wiremock::Request> {
+        for _ in 0..100 {
+            let requests = self.email_server.received_requests().await.unwrap();
+            if requests.len() >= n {
+                return requests;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("Expected {} emails to be sent", n);
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(email_request.body.as_ref()).unwrap();
 
@@ -60,22 +245,142 @@ impl TestApp {
             link.set_port(Some(self.port)).unwrap();
             link
         };
-        let html_link = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text_link = get_link(&body["TextBody"].as_str().unwrap());
+        let html_link = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text_link = get_link(body["TextBody"].as_str().unwrap());
         ConfirmationLinks { html: html_link, plain_text: plain_text_link }
     }
 
     pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
         reqwest::Client::new()
-        .post(&format!("{}/newsletters", &self.address))
+        .post(format!("{}/newsletters", &self.address))
+        .basic_auth(&self.test_user.username, Some(&self.test_user.password))
         .json(&body)
         .send()
         .await
         .expect("Failed to execute request.")
-    }  
+    }
+
+    /// Makes every task waiting for a retry due right away.
+    pub async fn fast_forward_retries(&self) {
+        sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+            .execute(&self.db_pool)
+            .await
+            .unwrap();
+    }
+
+    pub async fn get_delivery_summary(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/newsletters/{}/delivery",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issues(&self, query: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/newsletters/issues?{}", &self.address, query))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issue(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/newsletters/issues/{}",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_scheduled_issues(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/newsletters/scheduled", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn reschedule_issue(
+        &self,
+        newsletter_issue_id: &str,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!(
+                "{}/newsletters/scheduled/{}",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn cancel_scheduled_issue(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!(
+                "{}/newsletters/scheduled/{}",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_dry_run(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters?dry_run=true", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletter_preview(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters/preview", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawns the application after letting the caller tweak its configuration.
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
     let email_server = MockServer::start().await;
@@ -83,7 +388,9 @@ pub async fn spawn_app() -> TestApp {
         let mut c = get_configuration().expect("Failed to read configuration");
         // c.database.database_name = Uuid::new_v4().to_string();
         c.application.port = 0;
+        c.email_client.provider = EmailProvider::Postmark;
         c.email_client.base_url = email_server.uri();
+        customise(&mut c);
         c
     };
 
@@ -94,13 +401,25 @@ pub async fn spawn_app() -> TestApp {
     let port = application.port();
     let address = format!("http://127.0.0.1:{}", port);
 
-    let _ = tokio::spawn(application.run_until_stopped());
-    TestApp {
+    tokio::spawn(application.run_until_stopped());
+    let email_client = configuration.email_client.client(&db_pool);
+    let test_app = TestApp {
         address,
         port,
         db_pool,
         email_server,
-    }
+        test_user: TestUser::generate(),
+        email_client,
+        issue_delivery_settings: configuration.issue_delivery.clone(),
+        email_timeout: configuration.email_client.timeout(),
+        unsubscribe_links: UnsubscribeLinks::new(
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret.clone(),
+        ),
+        token_hasher: SubscriptionTokenHasher::new(configuration.application.hmac_secret.clone()),
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+    test_app
 }
 
 pub async fn configure_database(config: &mut DatabaseSettings) -> PgPool {
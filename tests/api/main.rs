@@ -1,5 +1,8 @@
+mod dev_mailbox;
 mod health_check;
 mod helpers;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_resend_confirmation;
+mod subscriptions_unsubscribe;
 mod newsletter;
\ No newline at end of file
@@ -3,7 +3,9 @@ use wiremock::{
     Mock, ResponseTemplate,
 };
 
-use crate::helpers::{spawn_app, ConfirmationLinks, TestApp};
+use uuid::Uuid;
+
+use crate::helpers::{spawn_app, ConfirmationLinks, PostmarkBatchResponder, TestApp};
 
 #[tokio::test]
 async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
@@ -24,7 +26,8 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
     }
     });
     let response = app.post_newsletters(newsletter_request_body).await;
-    assert_eq!(200, response.status().as_u16());
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
 }
 
 #[tokio::test]
@@ -32,9 +35,9 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
     let app = spawn_app().await;
     create_confirmed_subscriber(&app).await;
 
-    Mock::given(path("/email"))
+    Mock::given(path("/email/batch"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(PostmarkBatchResponder::default())
         .expect(1)
         .mount(&app.email_server)
         .await;
@@ -47,11 +50,20 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
     }
     });
     let response = app.post_newsletters(newsletter_request_body).await;
-    assert_eq!(200, response.status().as_u16());
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
 }
 
 async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
-    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    create_unconfirmed_subscriber_with_email(app, "ursula_le_guin@gmail.com").await
+}
+
+async fn create_unconfirmed_subscriber_with_email(app: &TestApp, email: &str) -> ConfirmationLinks {
+    let body = serde_urlencoded::to_string(serde_json::json!({
+        "name": "le guin",
+        "email": email,
+    }))
+    .unwrap();
     let _mock_guard = Mock::given(path("/email"))
         .and(method("POST"))
         .respond_with(ResponseTemplate::new(200))
@@ -59,22 +71,21 @@ async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
         .expect(1)
         .mount_as_scoped(&app.email_server)
         .await;
-    app.post_subscription(body.into())
+    let n_sent = app.email_server.received_requests().await.unwrap().len();
+    app.post_subscription(body)
         .await
         .error_for_status()
         .unwrap();
-    let email_request = &app
-        .email_server
-        .received_requests()
-        .await
-        .unwrap()
-        .pop()
-        .unwrap();
-    app.get_confirmation_links(&email_request)
+    let email_request = &app.wait_for_emails(n_sent + 1).await[n_sent];
+    app.get_confirmation_links(email_request)
 }
 
 async fn create_confirmed_subscriber(app: &TestApp) {
-    let confirmation_links = create_unconfirmed_subscriber(app).await;
+    create_confirmed_subscriber_with_email(app, "ursula_le_guin@gmail.com").await
+}
+
+async fn create_confirmed_subscriber_with_email(app: &TestApp, email: &str) {
+    let confirmation_links = create_unconfirmed_subscriber_with_email(app, email).await;
     reqwest::get(confirmation_links.html)
         .await
         .unwrap()
@@ -108,4 +119,1151 @@ async fn newsletters_return_400_for_invalid_data() {
     }
 }
 
-  
\ No newline at end of file
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+    assert_eq!(
+        "application/problem+json",
+        response.headers()["Content-Type"]
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/authentication-required");
+    assert_eq!(problem["status"], 401);
+}
+
+#[tokio::test]
+async fn non_existing_user_is_rejected() {
+    let app = spawn_app().await;
+    let username = Uuid::new_v4().to_string();
+    let password = Uuid::new_v4().to_string();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn invalid_password_is_rejected() {
+    let app = spawn_app().await;
+    let username = &app.test_user.username;
+    let password = Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let idempotency_key = Uuid::new_v4().to_string();
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body.clone(), &idempotency_key)
+        .await;
+    assert_eq!(202, response.status().as_u16());
+
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body, &idempotency_key)
+        .await;
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn concurrent_newsletter_submission_is_handled_gracefully() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let idempotency_key = Uuid::new_v4().to_string();
+    let response1 = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body.clone(), &idempotency_key);
+    let response2 =
+        app.post_newsletters_with_idempotency_key(newsletter_request_body, &idempotency_key);
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    assert_eq!(response1.status(), response2.status());
+    assert_eq!(
+        response1.text().await.unwrap(),
+        response2.text().await.unwrap()
+    );
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn an_empty_idempotency_key_is_rejected() {
+    let app = spawn_app().await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body, "")
+        .await;
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn a_failed_delivery_does_not_stop_the_others() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "first@example.com").await;
+    create_confirmed_subscriber_with_email(&app, "second@example.com").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default().failing("first@example.com", 300))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(202, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    let issue_id = body["newsletter_issue_id"].as_str().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let summary: serde_json::Value = app
+        .get_delivery_summary(issue_id)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(summary["sent"], 1);
+    assert_eq!(summary["failed"], 1);
+    assert_eq!(summary["dead_letter"], 0);
+    assert_eq!(summary["skipped"], 0);
+    assert_eq!(summary["pending"], 0);
+
+    let failure = sqlx::query!(
+        "SELECT error_chain FROM issue_deliveries WHERE outcome = 'failed'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert!(failure.error_chain.unwrap().contains("Caused by:"));
+}
+
+#[tokio::test]
+async fn subscribers_who_unsubscribe_before_dispatch_are_skipped() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "stays@example.com").await;
+    create_confirmed_subscriber_with_email(&app, "leaves@example.com").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(202, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    let issue_id = body["newsletter_issue_id"].as_str().unwrap();
+
+    // The delivery is queued: unsubscribing now must still keep the issue away.
+    let subscriber_id =
+        sqlx::query!("SELECT id FROM subscriptions WHERE email = 'leaves@example.com'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .id;
+    let mut url = reqwest::Url::parse(&app.unsubscribe_links.url(subscriber_id)).unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    reqwest::Client::new()
+        .post(url)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let batch_requests: Vec<_> = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|r| r.url.path() == "/email/batch")
+        .collect();
+    let batch: serde_json::Value = serde_json::from_slice(&batch_requests[0].body).unwrap();
+    let recipients: Vec<_> = batch
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|email| email["To"].as_str().unwrap())
+        .collect();
+    assert_eq!(recipients, vec!["stays@example.com"]);
+
+    let summary: serde_json::Value = app
+        .get_delivery_summary(issue_id)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(summary["sent"], 1);
+    assert_eq!(summary["skipped"], 1);
+    assert_eq!(summary["pending"], 0);
+}
+
+#[tokio::test]
+async fn delivery_summary_of_an_unknown_issue_is_a_404() {
+    let app = spawn_app().await;
+
+    let response = app.get_delivery_summary(&Uuid::new_v4().to_string()).await;
+    assert_eq!(404, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/not-found");
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[tokio::test]
+async fn transient_failures_are_retried() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    let task = sqlx::query!("SELECT n_retries, execute_after > now() AS \"scheduled!\" FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(task.n_retries, 1);
+    assert!(task.scheduled);
+
+    app.fast_forward_retries().await;
+    app.dispatch_all_pending_emails().await;
+
+    let delivery = sqlx::query!("SELECT outcome, n_attempts FROM issue_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.outcome, "sent");
+    assert_eq!(delivery.n_attempts, 2);
+}
+
+#[tokio::test]
+async fn client_errors_are_not_retried() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default().failing("ursula_le_guin@gmail.com", 300))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let n_queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_queued, 0);
+    let delivery = sqlx::query!("SELECT outcome, n_attempts FROM issue_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.outcome, "failed");
+    assert_eq!(delivery.n_attempts, 1);
+}
+
+#[tokio::test]
+async fn deliveries_are_dead_lettered_after_max_attempts() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let max_attempts = app.issue_delivery_settings.max_attempts;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(max_attempts as u64)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    let body: serde_json::Value = response.json().await.unwrap();
+    let issue_id = body["newsletter_issue_id"].as_str().unwrap();
+    for _ in 0..max_attempts {
+        app.fast_forward_retries().await;
+        app.dispatch_all_pending_emails().await;
+    }
+
+    let delivery = sqlx::query!("SELECT outcome, n_attempts FROM issue_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.outcome, "dead_letter");
+    assert_eq!(delivery.n_attempts, max_attempts);
+    // Dead letters are told apart from errors that were never worth retrying.
+    let summary: serde_json::Value = app
+        .get_delivery_summary(issue_id)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(summary["dead_letter"], 1);
+    assert_eq!(summary["failed"], 0);
+}
+
+#[tokio::test]
+async fn published_issues_are_listed_newest_first() {
+    let app = spawn_app().await;
+
+    let mut issue_ids = Vec::new();
+    for title in ["First issue", "Second issue", "Third issue"] {
+        let response: serde_json::Value = app
+            .post_newsletters(serde_json::json!({
+                "title": title,
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }))
+            .await
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        issue_ids.push(response["newsletter_issue_id"].as_str().unwrap().to_owned());
+    }
+
+    let first_page: serde_json::Value = app
+        .get_issues("per_page=2")
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(first_page["total"], 3);
+    assert_eq!(first_page["page"], 1);
+    assert_eq!(first_page["per_page"], 2);
+    let issues = first_page["issues"].as_array().unwrap();
+    assert_eq!(issues.len(), 2);
+    assert_eq!(issues[0]["title"], "Third issue");
+    assert_eq!(issues[0]["author"], app.test_user.username.as_str());
+    assert_eq!(issues[1]["title"], "Second issue");
+
+    let second_page: serde_json::Value = app
+        .get_issues("page=2&per_page=2")
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let issues = second_page["issues"].as_array().unwrap();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0]["newsletter_issue_id"], issue_ids[0].as_str());
+}
+
+#[tokio::test]
+async fn listing_issues_rejects_invalid_pagination() {
+    let app = spawn_app().await;
+
+    for query in [
+        "page=0",
+        "per_page=0",
+        "per_page=101",
+        "page=9223372036854775807&per_page=100",
+    ] {
+        let response = app.get_issues(query).await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not reject `{}`.",
+            query
+        );
+    }
+}
+
+#[tokio::test]
+async fn an_issue_can_be_read_back_with_its_delivery_counts() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response: serde_json::Value = app
+        .post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let issue_id = response["newsletter_issue_id"].as_str().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let issue: serde_json::Value = app
+        .get_issue(issue_id)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(issue["title"], "Newsletter title");
+    assert_eq!(issue["text_content"], "Newsletter body as plain text");
+    assert_eq!(issue["html_content"], "<p>Newsletter body as HTML</p>");
+    assert_eq!(issue["author"], app.test_user.username.as_str());
+    assert_eq!(issue["delivery"]["sent"], 1);
+    assert_eq!(issue["delivery"]["pending"], 0);
+}
+
+#[tokio::test]
+async fn an_unknown_issue_is_a_404() {
+    let app = spawn_app().await;
+
+    let response = app.get_issue(&Uuid::new_v4().to_string()).await;
+
+    assert_eq!(404, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/not-found");
+}
+
+#[tokio::test]
+async fn malformed_paths_and_query_strings_are_reported_as_problems() {
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let test_cases = vec![
+        (app.get_issue("notauuid").await, 404, "malformed issue id"),
+        (
+            client
+                .post(format!("{}/newsletters?dry_run=maybe", &app.address))
+                .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+                .json(&newsletter_request_body())
+                .send()
+                .await
+                .unwrap(),
+            400,
+            "malformed dry_run",
+        ),
+        (
+            client.get(format!("{}/nowhere", &app.address)).send().await.unwrap(),
+            404,
+            "unknown route",
+        ),
+    ];
+    for (response, status, case) in test_cases {
+        assert_eq!(response.status().as_u16(), status, "{}", case);
+        assert_eq!(
+            response.headers()["Content-Type"].to_str().unwrap(),
+            "application/problem+json",
+            "{}",
+            case
+        );
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["status"], status, "{}", case);
+        assert!(problem["request_id"].is_string(), "{}", case);
+    }
+}
+
+#[tokio::test]
+async fn reading_issues_requires_authentication() {
+    let app = spawn_app().await;
+
+    for url in [
+        format!("{}/newsletters/issues", &app.address),
+        format!("{}/newsletters/issues/{}", &app.address, Uuid::new_v4()),
+    ] {
+        let response = reqwest::Client::new()
+            .get(&url)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(401, response.status().as_u16());
+        assert_eq!(
+            r#"Basic realm="publish""#,
+            response.headers()["WWW-Authenticate"]
+        );
+    }
+}
+
+fn scheduled_newsletter_request_body(send_at: chrono::DateTime<chrono::Utc>) -> serde_json::Value {
+    let mut body = newsletter_request_body();
+    body["send_at"] = serde_json::json!(send_at);
+    body
+}
+
+async fn schedule_newsletter(app: &TestApp, send_at: chrono::DateTime<chrono::Utc>) -> String {
+    let response: serde_json::Value = app
+        .post_newsletters(scheduled_newsletter_request_body(send_at))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    response["newsletter_issue_id"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn scheduled_newsletters_are_held_until_send_at() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let send_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    let issue_id = schedule_newsletter(&app, send_at).await;
+
+    let nothing_is_sent = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .named("Delivery before send_at")
+        .expect(0)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+    drop(nothing_is_sent);
+
+    let scheduled: serde_json::Value = app
+        .get_scheduled_issues()
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let issues = scheduled["issues"].as_array().unwrap();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0]["newsletter_issue_id"], issue_id.as_str());
+    // It is not published yet.
+    let published: serde_json::Value = app.get_issues("").await.json().await.unwrap();
+    assert_eq!(published["total"], 0);
+    assert!(published["issues"].as_array().unwrap().is_empty());
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.release_scheduled_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    let scheduled: serde_json::Value = app.get_scheduled_issues().await.json().await.unwrap();
+    assert!(scheduled["issues"].as_array().unwrap().is_empty());
+    let published: serde_json::Value = app.get_issues("").await.json().await.unwrap();
+    assert_eq!(published["total"], 1);
+    assert_eq!(published["issues"][0]["newsletter_issue_id"], issue_id.as_str());
+}
+
+#[tokio::test]
+async fn newsletters_cannot_be_scheduled_in_the_past() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_newsletters(scheduled_newsletter_request_body(
+            chrono::Utc::now() - chrono::Duration::minutes(5),
+        ))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn scheduled_newsletters_can_be_rescheduled() {
+    let app = spawn_app().await;
+    let created_before = chrono::Utc::now();
+    let issue_id = schedule_newsletter(&app, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+    let new_send_at = chrono::Utc::now() + chrono::Duration::days(3);
+
+    let response = app
+        .reschedule_issue(&issue_id, serde_json::json!({ "send_at": new_send_at }))
+        .await;
+    assert_eq!(204, response.status().as_u16());
+
+    let scheduled: serde_json::Value = app.get_scheduled_issues().await.json().await.unwrap();
+    let send_at: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(scheduled["issues"][0]["send_at"].clone()).unwrap();
+    assert_eq!(send_at.timestamp(), new_send_at.timestamp());
+
+    // The issue still tells when it was written.
+    let issue: serde_json::Value = app.get_issue(&issue_id).await.json().await.unwrap();
+    let created_at: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(issue["created_at"].clone()).unwrap();
+    let published_at: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(issue["published_at"].clone()).unwrap();
+    assert!(created_at >= created_before && created_at <= chrono::Utc::now());
+    assert_eq!(published_at.timestamp(), new_send_at.timestamp());
+
+    let response = app
+        .reschedule_issue(
+            &issue_id,
+            serde_json::json!({ "send_at": chrono::Utc::now() - chrono::Duration::hours(1) }),
+        )
+        .await;
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn cancelled_newsletters_are_never_sent() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let issue_id = schedule_newsletter(&app, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+    let response = app.cancel_scheduled_issue(&issue_id).await;
+    assert_eq!(204, response.status().as_u16());
+
+    app.release_scheduled_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    let response = app.cancel_scheduled_issue(&issue_id).await;
+    assert_eq!(404, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/not-found");
+    let response = app.get_issue(&issue_id).await;
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn previews_only_go_to_the_requested_recipients() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let mut body = newsletter_request_body();
+    body["recipients"] = serde_json::json!(["editor@example.com", "reviewer@example.com"]);
+    let response = app.post_newsletter_preview(body).await;
+    assert_eq!(200, response.status().as_u16());
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let messages: Vec<serde_json::Value> =
+        serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    let recipients: Vec<_> = messages.iter().map(|m| m["To"].as_str().unwrap()).collect();
+    assert_eq!(recipients, ["editor@example.com", "reviewer@example.com"]);
+    for message in &messages {
+        assert_eq!(message["Subject"], "[TEST] Newsletter title");
+    }
+
+    let n_issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_issues, 0);
+    let n_tasks = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_tasks, 0);
+}
+
+#[tokio::test]
+async fn previews_return_400_for_invalid_recipients() {
+    let app = spawn_app().await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let test_cases = vec![
+        (serde_json::json!([]), "no recipients"),
+        (serde_json::json!(["not-an-email"]), "an invalid recipient"),
+        (
+            serde_json::json!(vec!["editor@example.com"; 11]),
+            "too many recipients",
+        ),
+    ];
+    for (recipients, description) in test_cases {
+        let mut body = newsletter_request_body();
+        body["recipients"] = recipients;
+        let response = app.post_newsletter_preview(body).await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn dry_runs_report_recipients_without_sending_anything() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "first@example.com").await;
+    create_confirmed_subscriber_with_email(&app, "second@example.com").await;
+    create_unconfirmed_subscriber_with_email(&app, "unconfirmed@example.com").await;
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'not-an-email', 'legacy', now(), 'confirmed')",
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let report: serde_json::Value = app
+        .post_newsletters_dry_run(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(report["recipients"], 2);
+    assert_eq!(report["invalid_recipients"], 1);
+    assert_eq!(report["sample"]["recipient"], "first@example.com");
+    assert_eq!(report["sample"]["subject"], "Newsletter title");
+    assert_eq!(report["sample"]["text_content"], "Newsletter body as plain text");
+
+    app.dispatch_all_pending_emails().await;
+    let n_issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_issues, 0);
+}
+
+#[tokio::test]
+async fn dry_runs_still_validate_the_newsletter() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_newsletters_dry_run(serde_json::json!({
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn newsletters_are_rendered_for_each_subscriber() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Issue <1>",
+            "content": {
+                "text": "Hi {{ subscriber.name }}, this is {{ issue.title }}",
+                "html": "<p>Hi {{ subscriber.name }}, this is {{ issue.title }}</p>",
+            }
+        }))
+        .await;
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let messages: Vec<serde_json::Value> =
+        serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert_eq!(messages[0]["TextBody"], "Hi le guin, this is Issue <1>");
+    assert_eq!(
+        messages[0]["HtmlBody"],
+        "<p>Hi le guin, this is Issue &lt;1&gt;</p>"
+    );
+}
+
+#[tokio::test]
+async fn invalid_templates_are_rejected_with_their_location() {
+    let app = spawn_app().await;
+
+    let test_cases = vec![
+        (
+            "{{ subscriber.password }}",
+            "unknown variable `subscriber.password`",
+        ),
+        ("Hello\n{% if subscriber.name %}", "at line 2"),
+    ];
+    for (text, expected) in test_cases {
+        let response = app
+            .post_newsletters(serde_json::json!({
+                "title": "Newsletter title",
+                "content": {
+                    "text": text,
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }))
+            .await;
+        assert_eq!(400, response.status().as_u16());
+        let message = response.text().await.unwrap();
+        assert!(
+            message.contains(expected),
+            "`{}` does not mention `{}`.",
+            message,
+            expected
+        );
+    }
+}
+
+#[tokio::test]
+async fn markdown_newsletters_are_rendered_to_html_and_text() {
+    let app = spawn_app().await;
+    let markdown = "# Big news\n\nRead [the post](https://example.com/post).\n\n<script>alert(1)</script>";
+
+    let response: serde_json::Value = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": markdown }
+        }))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let issue_id = response["newsletter_issue_id"].as_str().unwrap();
+
+    let issue: serde_json::Value = app
+        .get_issue(issue_id)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(issue["markdown_content"], markdown);
+    let html = issue["html_content"].as_str().unwrap();
+    assert!(html.contains("<h1>Big news</h1>"));
+    assert!(!html.contains("<script>"));
+    assert_eq!(
+        issue["text_content"],
+        "Big news\n========\n\nRead the post [1].\n\n[1] https://example.com/post"
+    );
+}
+
+#[tokio::test]
+async fn markdown_cannot_be_mixed_with_html_or_text() {
+    let app = spawn_app().await;
+
+    let test_cases = vec![
+        (
+            serde_json::json!({ "markdown": "# Hi", "html": "<h1>Hi</h1>" }),
+            "both markdown and html",
+        ),
+        (
+            serde_json::json!({ "text": "Hi" }),
+            "text without html nor markdown",
+        ),
+    ];
+    for (content, description) in test_cases {
+        let response = app
+            .post_newsletters(serde_json::json!({
+                "title": "Newsletter title",
+                "content": content
+            }))
+            .await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the content had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_missing_text_body_is_derived_from_the_html_one() {
+    let app = spawn_app().await;
+
+    let response: serde_json::Value = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "html": "<p>Read <a href=\"https://example.com/post\">the post</a>.</p>\
+                    <ul><li>one</li><li>two</li></ul>",
+            }
+        }))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let issue_id = response["newsletter_issue_id"].as_str().unwrap();
+
+    let issue: serde_json::Value = app
+        .get_issue(issue_id)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let text = issue["text_content"].as_str().unwrap();
+    assert!(text.contains("Read the post (https://example.com/post)."), "{}", text);
+    assert!(text.contains("* one\n* two"), "{}", text);
+}
+
+#[tokio::test]
+async fn newsletters_carry_a_working_one_click_unsubscribe_link() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Unsubscribe: {{ unsubscribe_url }}",
+                "html": "<a href=\"{{ unsubscribe_url }}\">Unsubscribe</a>",
+            }
+        }))
+        .await;
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let messages: Vec<serde_json::Value> =
+        serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    let headers = messages[0]["Headers"].as_array().unwrap();
+    assert_eq!(headers[0]["Name"], "List-Unsubscribe");
+    assert_eq!(headers[1]["Name"], "List-Unsubscribe-Post");
+    assert_eq!(headers[1]["Value"], "List-Unsubscribe=One-Click");
+    let unsubscribe_url = headers[0]["Value"]
+        .as_str()
+        .unwrap()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    assert_eq!(
+        messages[0]["TextBody"],
+        format!("Unsubscribe: {}", unsubscribe_url)
+    );
+
+    let mut unsubscribe_url = reqwest::Url::parse(unsubscribe_url).unwrap();
+    unsubscribe_url.set_port(Some(app.port)).unwrap();
+    reqwest::Client::new()
+        .post(unsubscribe_url)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Unsubscribed subscribers are left out of the next issues.
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn markdown_newsletters_can_link_to_the_unsubscribe_url() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Bye?\n\n[Unsubscribe]({{ unsubscribe_url }})" }
+        }))
+        .await;
+    assert_eq!(202, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let messages: Vec<serde_json::Value> =
+        serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    let unsubscribe_url = messages[0]["Headers"][0]["Value"]
+        .as_str()
+        .unwrap()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    assert_eq!(
+        messages[0]["TextBody"],
+        format!("Bye?\n\nUnsubscribe [1]\n\n[1] {}", unsubscribe_url)
+    );
+    // The HTML body escapes the URL like any other attribute value.
+    let html = messages[0]["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .replace("&#x2f;", "/")
+        .replace("&amp;", "&");
+    assert!(
+        html.contains(&format!("<a href=\"{}\"", unsubscribe_url)),
+        "{}",
+        html
+    );
+}
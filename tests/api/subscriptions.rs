@@ -3,7 +3,10 @@ use wiremock::{
     Mock, ResponseTemplate,
 };
 
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with};
+use std::collections::HashMap;
+use zero2prod::configuration::OptIn;
+use zero2prod::domain::SubscriptionStatus;
 
 #[tokio::test]
 async fn subscribe_return_a_200_for_valid_form_data() {
@@ -34,14 +37,16 @@ async fn subscribe_persists_the_new_subscriber() {
 
     app.post_subscription(body.to_string()).await;
 
-    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions",)
-        .fetch_one(&app.db_pool)
-        .await
-        .expect("Failed to fetch save subscription");
+    let saved = sqlx::query!(
+        r#"SELECT email, name, status AS "status: SubscriptionStatus" FROM subscriptions"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch save subscription");
 
     assert_eq!(saved.email, "ursula_le_guin@gmail.com");
     assert_eq!(saved.name, "le guin");
-    assert_eq!(saved.status, "pending_confirmation");
+    assert_eq!(saved.status, SubscriptionStatus::PendingConfirmation);
 }
 #[tokio::test]
 async fn subscribe_return_a_400_when_data_is_missing() {
@@ -88,6 +93,7 @@ async fn subscribe_send_confirm_email_for_valid_data() {
         .mount(&app.email_server)
         .await;
     app.post_subscription(body.into()).await;
+    app.wait_for_emails(1).await;
 }
 
 #[tokio::test]
@@ -101,7 +107,7 @@ async fn subscribe_sends_a_conformation_email_with_a_link() {
         .await;
     app.post_subscription(body.into()).await;
 
-    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email_request = &app.wait_for_emails(1).await[0];
     let confirmation_links = app.get_confirmation_links(email_request);
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
@@ -111,11 +117,426 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     let app = spawn_app().await;
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
 
-    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;")
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN token_hash;")
         .execute(&app.db_pool)
         .await
         .unwrap();
 
     let response = app.post_subscription(body.to_string()).await;
     assert_eq!(response.status().as_u16(), 500);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/internal-error");
+    // The error chain stays in the logs.
+    assert!(problem.get("detail").is_none());
+    assert!(!problem.to_string().contains("token_hash"));
+}
+
+#[tokio::test]
+async fn subscribe_marks_inactive_recipients_as_bounced() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscription(body.into()).await;
+
+    // The reply does not wait for the provider, so it cannot tell.
+    assert_eq!(response.status().as_u16(), 200);
+    let mut status = SubscriptionStatus::PendingConfirmation;
+    for _ in 0..100 {
+        status = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .status;
+        if status != SubscriptionStatus::PendingConfirmation {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(status, SubscriptionStatus::Bounced);
+    let n_tokens = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscription_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_tokens, 0);
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_sends_a_fresh_confirmation_link() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(body.into()).await;
+    app.wait_for_emails(1).await;
+    app.backdate_confirmation_tokens().await;
+    let response = app.post_subscription(body.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let requests = app.wait_for_emails(2).await;
+    let first = app.get_confirmation_links(&requests[0]);
+    let second = app.get_confirmation_links(&requests[1]);
+    assert_ne!(first.html, second.html);
+    // Only the latest link confirms the subscription.
+    let response = reqwest::get(first.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    reqwest::get(second.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn subscribing_twice_within_the_cooldown_sends_a_single_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let first = app.post_subscription(body.into()).await;
+    let second = app.post_subscription(body.into()).await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(first.text().await.unwrap(), second.text().await.unwrap());
+    app.wait_for_emails(1).await;
+}
+
+#[tokio::test]
+async fn concurrent_subscriptions_for_the_same_email_both_succeed() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        // The second request finds a link that was just sent.
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let (first, second) = tokio::join!(
+        app.post_subscription(body.into()),
+        app.post_subscription(body.into())
+    );
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    let n_subscribers = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(n_subscribers, 1);
+    app.wait_for_emails(1).await;
+}
+
+#[tokio::test]
+async fn subscribing_again_when_confirmed_returns_a_200_without_an_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(body.into()).await;
+    let email_request = &app.wait_for_emails(1).await[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = app.post_subscription(body.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriptionStatus::Confirmed);
+}
+
+#[tokio::test]
+async fn subscribing_again_after_unsubscribing_requires_a_new_confirmation() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(body.into()).await;
+    app.wait_for_emails(1).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_subscription(body.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriptionStatus::PendingConfirmation);
+    let email_request = &app.wait_for_emails(2).await[1];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_does_not_store_the_confirmation_token_in_plain_text() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(body.into()).await;
+
+    let email_request = &app.wait_for_emails(1).await[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let (_, token) = confirmation_links
+        .html
+        .query_pairs()
+        .find(|(name, _)| name == "subscription_token")
+        .unwrap();
+    let saved = sqlx::query!("SELECT token_hash FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_ne!(saved.token_hash, token);
+}
+
+#[tokio::test]
+async fn subscribe_accepts_json_and_replies_with_json() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscription_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"].to_str().unwrap(),
+        "application/json"
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["message"].is_string());
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+    app.wait_for_emails(1).await;
+}
+
+#[tokio::test]
+async fn subscribe_names_the_invalid_fields_of_a_json_body() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (serde_json::json!({"email": "ursula_le_guin@gmail.com"}), vec!["name"]),
+        (serde_json::json!({"name": "le guin", "email": "definitely-not-an-email"}), vec!["email"]),
+        (serde_json::json!({"name": "", "email": ""}), vec!["email", "name"]),
+        (serde_json::json!({"name": "x", "email": 5}), vec!["email"]),
+    ];
+    for (body, invalid_fields) in test_cases {
+        let response = app.post_subscription_json(body.clone()).await;
+
+        assert_eq!(response.status().as_u16(), 400, "Payload: {}", body);
+        let errors: serde_json::Value = response.json().await.unwrap();
+        let errors = errors["errors"].as_object().unwrap();
+        let mut fields: Vec<_> = errors.keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        assert_eq!(fields, invalid_fields, "Payload: {}", body);
+        assert!(errors.values().all(|reason| !reason.as_str().unwrap().is_empty()));
+    }
+}
+
+#[tokio::test]
+async fn subscribe_names_the_invalid_fields_of_a_form_body_in_a_problem() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscription("name=&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(
+        response.headers()["Content-Type"].to_str().unwrap(),
+        "application/problem+json"
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/invalid-request");
+    assert_eq!(problem["status"], 400);
+    assert!(problem["title"].is_string());
+    assert!(problem["request_id"].is_string());
+    let errors = problem["errors"].as_object().unwrap();
+    assert_eq!(errors.keys().collect::<Vec<_>>(), vec!["name"]);
+}
+
+#[tokio::test]
+async fn subscribe_rejects_unreadable_bodies_with_a_problem() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("application/json", r#"{"email": "ursula_le_guin@gmail.com", "name":"#, 400),
+        ("application/x-www-form-urlencoded", "name=le%20guin&name=le%20guin", 400),
+        ("text/plain", "name=le%20guin&email=ursula_le_guin%40gmail.com", 415),
+    ];
+    for (content_type, body, status) in test_cases {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("Content-Type", content_type)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status().as_u16(), status, "Content type: {}", content_type);
+        assert_eq!(
+            response.headers()["Content-Type"].to_str().unwrap(),
+            "application/problem+json"
+        );
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["status"], status);
+        assert!(problem["detail"].is_string());
+        // Not mistaken for a body with invalid fields.
+        assert!(problem.get("errors").is_none());
+        assert!(problem["request_id"].is_string());
+    }
+}
+
+#[tokio::test]
+async fn form_subscriptions_are_answered_in_form_encoding() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"].to_str().unwrap(),
+        "application/x-www-form-urlencoded"
+    );
+    let reply: HashMap<String, String> =
+        serde_urlencoded::from_str(&response.text().await.unwrap()).unwrap();
+    assert!(reply.contains_key("message"));
+}
+
+#[tokio::test]
+async fn single_opt_in_confirms_subscribers_and_sends_a_welcome_email() {
+    let app = spawn_app_with(|c| c.application.opt_in = OptIn::Single).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriptionStatus::Confirmed);
+    let email_request = &app.wait_for_emails(1).await[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(!body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/confirm"));
+    assert!(body["Headers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|header| header["Name"] == "List-Unsubscribe"));
+}
+
+#[tokio::test]
+async fn single_opt_in_asks_returning_subscribers_to_confirm_again() {
+    let app = spawn_app_with(|c| c.application.opt_in = OptIn::Single).await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(body.into()).await;
+    app.wait_for_emails(1).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_subscription(body.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriptionStatus::PendingConfirmation);
+    let email_request = &app.wait_for_emails(2).await[1];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriptionStatus::Confirmed);
 }
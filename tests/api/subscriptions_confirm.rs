@@ -4,6 +4,8 @@ use wiremock::{
 };
 
 use crate::helpers::spawn_app;
+use zero2prod::domain::SubscriptionStatus;
+use zero2prod::subscription_tokens::hash_outstanding_tokens;
 
 #[tokio::test]
 async fn confirmations_without_token_are_rejected_with_a_400() {
@@ -12,6 +14,14 @@ async fn confirmations_without_token_are_rejected_with_a_400() {
         .await
         .unwrap();
     assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(
+        response.headers()["Content-Type"].to_str().unwrap(),
+        "application/problem+json"
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/invalid-request");
+    assert!(problem["detail"].as_str().unwrap().contains("subscription_token"));
+    assert!(problem["request_id"].is_string());
 }
 
 #[tokio::test]
@@ -25,7 +35,7 @@ async fn the_link_returned_by_subscriber_returns_a_200_if_called() {
         .await;
     app.post_subscription(body.into()).await;
 
-    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email_request = &app.wait_for_emails(1).await[0];
 
     let confirmation_links = app.get_confirmation_links(email_request);
 
@@ -45,7 +55,7 @@ async fn clicking_on_the_confirmation_link_confirm_a_subscriber() {
         .await;
     app.post_subscription(body.into()).await;
 
-    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email_request = &app.wait_for_emails(1).await[0];
 
     let confirmation_links = app.get_confirmation_links(email_request);
 
@@ -55,12 +65,162 @@ async fn clicking_on_the_confirmation_link_confirm_a_subscriber() {
         .error_for_status()
         .unwrap();
 
-    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
-        .fetch_one(&app.db_pool)
-        .await
-        .expect("Failed to fetch saved subscription");
+    let saved = sqlx::query!(
+        r#"SELECT email, name, status AS "status: SubscriptionStatus" FROM subscriptions"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscription");
 
     assert_eq!(saved.email, "ursula_le_guin@gmail.com");
     assert_eq!(saved.name, "le guin");
-    assert_eq!(saved.status, "confirmed");
+    assert_eq!(saved.status, SubscriptionStatus::Confirmed);
+}
+
+#[tokio::test]
+async fn an_unused_confirmation_link_does_not_resubscribe_an_unsubscribed_subscriber() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(body.into()).await;
+    let email_request = &app.wait_for_emails(1).await[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    // A link that has not been used yet, so that only the status stands in
+    // the way.
+    let subscription_token = "aFreshTokenThatWasNeverUsed";
+    sqlx::query!(
+        "INSERT INTO subscription_tokens (token_hash, subscriber_id, created_at, expires_at)
+        SELECT $1, id, now(), now() + interval '1 hour' FROM subscriptions",
+        app.token_hasher.hash(subscription_token)
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let mut link = confirmation_links.html;
+    link.query_pairs_mut()
+        .clear()
+        .append_pair("subscription_token", subscription_token);
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/confirmation-not-allowed");
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriptionStatus::Unsubscribed);
+}
+
+#[tokio::test]
+async fn a_confirmation_link_can_only_be_used_once() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(body.into()).await;
+    let email_request = &app.wait_for_emails(1).await[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn an_expired_confirmation_link_is_rejected_with_a_410() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(body.into()).await;
+    let email_request = &app.wait_for_emails(1).await[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/expired-confirmation-link");
+    assert!(problem["detail"].is_string());
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriptionStatus::PendingConfirmation);
+}
+
+#[tokio::test]
+async fn an_unknown_confirmation_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=not-a-real-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn tokens_stored_in_plain_text_keep_working_once_hashed() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(body.into()).await;
+    sqlx::query!(
+        "INSERT INTO subscription_tokens (token_hash, subscriber_id, expires_at, is_hashed)
+        SELECT 'a-legacy-plain-text-token', id, now() + interval '1 hour', false
+        FROM subscriptions"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    hash_outstanding_tokens(&app.db_pool, &app.token_hasher)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=a-legacy-plain-text-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
 }
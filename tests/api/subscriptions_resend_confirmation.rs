@@ -0,0 +1,111 @@
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::spawn_app;
+
+const SUBSCRIPTION: &str = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+const RESEND: &str = "email=ursula_le_guin%40gmail.com";
+
+#[tokio::test]
+async fn resending_to_a_pending_subscriber_sends_a_working_link() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(SUBSCRIPTION.into()).await;
+    app.wait_for_emails(1).await;
+    app.backdate_confirmation_tokens().await;
+
+    let response = app.post_resend_confirmation(RESEND.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = &app.wait_for_emails(2).await[1];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn resending_within_the_cooldown_sends_nothing() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(SUBSCRIPTION.into()).await;
+
+    let response = app.post_resend_confirmation(RESEND.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    app.wait_for_emails(1).await;
+}
+
+#[tokio::test]
+async fn resending_to_unknown_or_confirmed_addresses_looks_the_same() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let unknown = app.post_resend_confirmation(RESEND.into()).await;
+
+    app.post_subscription(SUBSCRIPTION.into()).await;
+    let email_request = &app.wait_for_emails(1).await[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.backdate_confirmation_tokens().await;
+    let confirmed = app.post_resend_confirmation(RESEND.into()).await;
+
+    assert_eq!(unknown.status().as_u16(), 200);
+    assert_eq!(confirmed.status().as_u16(), 200);
+    assert_eq!(unknown.text().await.unwrap(), confirmed.text().await.unwrap());
+}
+
+#[tokio::test]
+async fn resending_to_an_invalid_address_returns_a_400() {
+    let app = spawn_app().await;
+
+    let response = app.post_resend_confirmation("email=not-an-email".into()).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn email_failures_do_not_show_in_the_response() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription(SUBSCRIPTION.into()).await;
+    app.wait_for_emails(1).await;
+    app.backdate_confirmation_tokens().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_resend_confirmation(RESEND.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
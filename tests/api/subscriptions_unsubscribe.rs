@@ -0,0 +1,125 @@
+use uuid::Uuid;
+
+use crate::helpers::{spawn_app, TestApp};
+use zero2prod::domain::SubscriptionStatus;
+
+async fn insert_confirmed_subscriber(app: &TestApp) -> Uuid {
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'ursula_le_guin@gmail.com', 'le guin', now(), 'confirmed')",
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    subscriber_id
+}
+
+async fn subscriber_status(app: &TestApp, subscriber_id: Uuid) -> SubscriptionStatus {
+    sqlx::query!(
+        r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+/// Points an unsubscribe link at the port the test application listens on.
+fn local_url(app: &TestApp, url: String) -> reqwest::Url {
+    let mut url = reqwest::Url::parse(&url).unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    url
+}
+
+#[tokio::test]
+async fn clicking_on_the_unsubscribe_link_asks_for_confirmation() {
+    let app = spawn_app().await;
+    let subscriber_id = insert_confirmed_subscriber(&app).await;
+    let url = local_url(&app, app.unsubscribe_links.url(subscriber_id));
+
+    let response = reqwest::get(url.clone()).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    // Opening the link (or having a mail scanner open it) changes nothing.
+    assert_eq!(subscriber_status(&app, subscriber_id).await, SubscriptionStatus::Confirmed);
+
+    // Submitting the form does.
+    let action = format!("{}?{}", url.path(), url.query().unwrap()).replace('&', "&amp;");
+    assert!(html.contains(&format!(r#"<form action="{}" method="post">"#, action)), "{}", html);
+    let response = reqwest::Client::new()
+        .post(url)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app, subscriber_id).await, SubscriptionStatus::Unsubscribed);
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_requests_unsubscribe_a_subscriber() {
+    let app = spawn_app().await;
+    let subscriber_id = insert_confirmed_subscriber(&app).await;
+    let url = local_url(&app, app.unsubscribe_links.url(subscriber_id));
+
+    let response = reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app, subscriber_id).await, SubscriptionStatus::Unsubscribed);
+}
+
+#[tokio::test]
+async fn unsubscribe_links_with_an_invalid_signature_are_rejected() {
+    let app = spawn_app().await;
+    let subscriber_id = insert_confirmed_subscriber(&app).await;
+    // A valid signature, but for someone else.
+    let mut url = local_url(&app, app.unsubscribe_links.url(Uuid::new_v4()));
+    let signature = url
+        .query_pairs()
+        .find(|(name, _)| name == "signature")
+        .unwrap()
+        .1
+        .into_owned();
+    url.query_pairs_mut()
+        .clear()
+        .append_pair("subscriber_id", &subscriber_id.to_string())
+        .append_pair("signature", &signature);
+
+    let response = reqwest::get(url.clone()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/invalid-unsubscribe-link");
+
+    let response = reqwest::Client::new()
+        .post(url)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/invalid-unsubscribe-link");
+    assert_eq!(subscriber_status(&app, subscriber_id).await, SubscriptionStatus::Confirmed);
+}
+
+#[tokio::test]
+async fn unsubscribe_requests_without_parameters_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!("{}/subscriptions/unsubscribe", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+}